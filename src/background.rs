@@ -0,0 +1,218 @@
+use crate::composite::{self, Argb};
+use crate::image::Image;
+use crate::parser::{ColorStop, Gradient, ImageFit, Length, WindowProps};
+
+/// Premultiplied RGBA from 0.0 to 255.0, gradients are interpolated
+/// in these and dithered only when turned into pixels
+type Channels = [f32; 4];
+
+/// Thresholds of a 4x4 ordered dithering, spread over a square of pixels
+static BAYER: [f32; 16] = [0.0, 8.0, 2.0, 10.0, 12.0, 4.0, 14.0, 6.0, 3.0, 11.0, 1.0, 9.0, 15.0, 7.0, 13.0, 5.0];
+
+/// What fills the box inside the border
+pub enum Background {
+    Solid(Argb),
+    Gradient { shape: Shape, stops: Vec<(f32, Channels)> },
+}
+
+/// How the pixels of the box map to the positions of a gradient, from 0.0 to 1.0
+pub enum Shape {
+    /// Projection on the line through `center` along `direction`, `length` long
+    Linear { center: (f32, f32), direction: (f32, f32), length: f32 },
+    /// Distance from `center`, `radius` is the distance of the farthest corner
+    Radial { center: (f32, f32), radius: f32 },
+}
+
+impl Background {
+    /// The background of a box of size `dimensions`
+    pub fn new(window: &WindowProps, dimensions: (u32, u32)) -> Self {
+
+        let Some(gradient) = &window.background else {
+            return Background::Solid(composite::premultiply(window.solid_background()));
+        };
+
+        let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+        let shape = match gradient {
+            Gradient::Linear { angle, .. } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                Shape::Linear {
+                    center: (width / 2.0, height / 2.0),
+                    // Clockwise from the top, with the y axis pointing down
+                    direction: (sin, -cos),
+                    // The corners are at the ends of the gradient, as in CSS
+                    length: (width * sin).abs() + (height * cos).abs(),
+                }
+            }
+            Gradient::Radial { center, .. } => {
+                let center = (length_px(center.0, dimensions.0), length_px(center.1, dimensions.1));
+                let radius = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)]
+                    .iter()
+                    .map(|corner| distance(center, *corner))
+                    .fold(0.0, f32::max);
+                Shape::Radial { center, radius }
+            }
+        };
+
+        Background::Gradient { shape, stops: resolve_stops(gradient.stops(), window.background_opacity) }
+    }
+
+    /// Pixel at `x`, `y` in the coordinates of the box
+    pub fn pixel(&self, x: u32, y: u32) -> Argb {
+
+        let (shape, stops) = match self {
+            Background::Solid(pixel) => return *pixel,
+            Background::Gradient { shape, stops } => (shape, stops),
+        };
+
+        let point = (x as f32 + 0.5, y as f32 + 0.5);
+        let position = match shape {
+            Shape::Linear { center, direction, length } if *length > 0.0 => {
+                ((point.0 - center.0) * direction.0 + (point.1 - center.1) * direction.1) / length + 0.5
+            }
+            Shape::Radial { center, radius } if *radius > 0.0 => distance(point, *center) / radius,
+            _ => 0.0,
+        };
+
+        dither(color_at(stops, position), x, y)
+    }
+}
+
+/// The background image scaled and placed in the box as configured by `fit`
+pub struct BackgroundImage {
+    image: Image,
+    /// Position of the top left corner of the image in the box
+    origin: (i32, i32),
+    tile: bool,
+}
+
+impl BackgroundImage {
+    /// Fit `image` in a box of size `dimensions`, with the `image_opacity` applied
+    pub fn new(window: &WindowProps, image: &Image, dimensions: (u32, u32)) -> Self {
+
+        let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+        let (image_width, image_height) = (image.width.max(1) as f32, image.height.max(1) as f32);
+
+        let size = match window.fit {
+            ImageFit::Stretch => dimensions,
+            ImageFit::Tile => (image.width, image.height),
+            ImageFit::Cover | ImageFit::Contain => {
+                let (x, y) = (width / image_width, height / image_height);
+                let factor = if window.fit == ImageFit::Cover { x.max(y) } else { x.min(y) };
+                (((image_width * factor).round() as u32).max(1), ((image_height * factor).round() as u32).max(1))
+            }
+        };
+        let origin = match window.fit {
+            ImageFit::Tile => (0, 0),
+            _ => ((dimensions.0 as i32 - size.0 as i32) / 2, (dimensions.1 as i32 - size.1 as i32) / 2),
+        };
+
+        let mut image = image.resize(size.0, size.1);
+        if window.image_opacity < 100 {
+            let opacity = window.image_opacity as f32 / 100.0;
+            image.pixels.iter_mut().for_each(|pixel| *pixel = composite::scale(*pixel, opacity));
+        }
+
+        BackgroundImage { image, origin, tile: window.fit == ImageFit::Tile }
+    }
+
+    /// Pixel at `x`, `y` in the coordinates of the box, transparent where there's no image
+    pub fn pixel(&self, x: u32, y: u32) -> Argb {
+
+        let (width, height) = (self.image.width as i32, self.image.height as i32);
+        if width == 0 || height == 0 {
+            return 0;
+        }
+
+        let (mut x, mut y) = (x as i32 - self.origin.0, y as i32 - self.origin.1);
+        if self.tile {
+            (x, y) = (x.rem_euclid(width), y.rem_euclid(height));
+        }
+        if (0..width).contains(&x) && (0..height).contains(&y) {
+            self.image.pixels[(x + y * width) as usize]
+        } else {
+            0
+        }
+    }
+}
+
+fn length_px(length: Length, total: u32) -> f32 {
+    match length {
+        Length::Px(px) => px as f32,
+        Length::Percent(percentage) => percentage * total as f32 / 100.0,
+    }
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Positions (from 0.0 to 1.0) and premultiplied colors of the stops, with the opacity applied.
+/// As in CSS the first and the last stop are at the ends without a position, the other
+/// ones missing it are spread evenly between their neighbours, and no stop is before the previous one
+fn resolve_stops(stops: &[ColorStop], opacity: u32) -> Vec<(f32, Channels)> {
+
+    let mut positions: Vec<Option<f32>> = stops.iter().map(|stop| stop.position.map(|position| position / 100.0)).collect();
+    if let Some(first) = positions.first_mut() {
+        first.get_or_insert(0.0);
+    }
+    if let Some(last) = positions.last_mut() {
+        last.get_or_insert(1.0);
+    }
+
+    let mut previous = 0.0;
+    for position in positions.iter_mut().flatten() {
+        *position = position.max(previous);
+        previous = *position;
+    }
+
+    let mut index = 1;
+    while index < positions.len() {
+        if positions[index].is_none() {
+            let start = index - 1;
+            let end = (index..positions.len()).find(|i| positions[*i].is_some()).expect("The last stop has a position");
+            let (from, to) = (positions[start].unwrap_or(0.0), positions[end].unwrap_or(1.0));
+            for (i, position) in positions.iter_mut().enumerate().take(end).skip(index) {
+                *position = Some(from + (to - from) * (i - start) as f32 / (end - start) as f32);
+            }
+            index = end;
+        }
+        index += 1;
+    }
+
+    stops
+        .iter()
+        .zip(positions)
+        .map(|(stop, position)| {
+            let color = stop.color.with_opacity(opacity);
+            let alpha = color.a as f32 / 255.0;
+            (position.unwrap_or(0.0), [color.r as f32 * alpha, color.g as f32 * alpha, color.b as f32 * alpha, color.a as f32])
+        })
+        .collect()
+}
+
+/// Color of the gradient at `position`, the colors before the first stop
+/// and after the last one are the ones of those stops
+fn color_at(stops: &[(f32, Channels)], position: f32) -> Channels {
+
+    let Some(next) = stops.iter().position(|(stop, _)| *stop >= position) else {
+        return stops.last().map_or([0.0; 4], |(_, color)| *color);
+    };
+    if next == 0 {
+        return stops[0].1;
+    }
+
+    let (start, from) = stops[next - 1];
+    let (end, to) = stops[next];
+    let t = if end > start { (position - start) / (end - start) } else { 1.0 };
+    [0, 1, 2, 3].map(|i| from[i] + (to[i] - from[i]) * t)
+}
+
+/// Round the channels to a pixel, with an ordered dithering so that
+/// the steps between the 8 bit values don't show as bands
+fn dither(channels: Channels, x: u32, y: u32) -> Argb {
+
+    let threshold = (BAYER[((y % 4) * 4 + x % 4) as usize] + 0.5) / 16.0 - 0.5;
+    let [r, g, b, a] = channels.map(|c| (c + threshold).round().clamp(0.0, 255.0) as u8);
+    // Premultiplied channels can't exceed the alpha
+    u32::from_be_bytes([a, r.min(a), g.min(a), b.min(a)])
+}
@@ -0,0 +1,441 @@
+use std::ops::Range;
+
+use crate::color::Color;
+use crate::composite::{self, Argb};
+use crate::image::Image;
+
+/// A point of the canvas, the centers of the pixels are at half coordinates
+pub type Point = (f32, f32);
+
+/// How a shape is drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Paint {
+    /// The inside of the shape
+    Fill,
+    /// The outline of the shape, with a stroke this wide centered on it
+    Stroke(f32),
+}
+
+/// Premultiplied Argb8888 pixels in rows `width` long, with antialiased drawing primitives.
+/// Only the pixels inside the clip are changed, the whole canvas unless set otherwise
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Argb>,
+    clip: (Range<i32>, Range<i32>),
+}
+
+impl Canvas {
+    /// A transparent canvas
+    pub fn new(width: u32, height: u32) -> Self {
+        Canvas::from_fn(width, height, |_, _| 0)
+    }
+
+    /// A canvas with each pixel set to `pixel(x, y)`
+    pub fn from_fn(width: u32, height: u32, mut pixel: impl FnMut(u32, u32) -> Argb) -> Self {
+
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(pixel(x, y));
+            }
+        }
+
+        Canvas { width, height, pixels, clip: (0..width as i32, 0..height as i32) }
+    }
+
+    /// Draw only on the pixels in `x` and `y`
+    pub fn set_clip(&mut self, x: Range<i32>, y: Range<i32>) {
+        self.clip = (x.start.max(0)..x.end.min(self.width as i32), y.start.max(0)..y.end.min(self.height as i32));
+    }
+
+    /// Draw on the whole canvas again
+    pub fn reset_clip(&mut self) {
+        self.clip = (0..self.width as i32, 0..self.height as i32);
+    }
+
+    fn pixel_mut(&mut self, x: i32, y: i32) -> Option<&mut Argb> {
+        if self.clip.0.contains(&x) && self.clip.1.contains(&y) {
+            Some(&mut self.pixels[(x as u32 + y as u32 * self.width) as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Composite `color` over the pixel at `x`, `y` where a shape covers the fraction `coverage` of it
+    pub fn blend(&mut self, x: i32, y: i32, color: Color, coverage: f32) {
+        if let Some(pixel) = self.pixel_mut(x, y) {
+            composite::blend(pixel, color, coverage);
+        }
+    }
+
+    /// Composite `color` as `blend`, mixing the colors in linear light
+    pub fn blend_linear(&mut self, x: i32, y: i32, color: Color, coverage: f32) {
+        if let Some(pixel) = self.pixel_mut(x, y) {
+            composite::blend_linear(pixel, color, coverage);
+        }
+    }
+
+    /// Composite premultiplied `pixels`, in rows `width` long, with their top left corner at `origin`
+    pub fn draw_pixels(&mut self, origin: (i32, i32), width: u32, pixels: &[Argb]) {
+
+        for (index, src) in pixels.iter().enumerate().filter(|(_, src)| **src != 0) {
+            let x = origin.0 + (index as u32 % width) as i32;
+            let y = origin.1 + (index as u32 / width) as i32;
+            if let Some(dst) = self.pixel_mut(x, y) {
+                *dst = composite::over(*dst, *src);
+            }
+        }
+    }
+
+    /// Composite `image` with its top left corner at `origin`
+    pub fn draw_image(&mut self, image: &Image, origin: (i32, i32)) {
+        self.draw_pixels(origin, image.width, &image.pixels);
+    }
+
+    /// Line from `from` to `to` `width` wide, cut square at the ends
+    pub fn line(&mut self, from: Point, to: Point, width: f32, color: Color) {
+
+        let direction = (to.0 - from.0, to.1 - from.1);
+        if length(direction) == 0.0 {
+            return;
+        }
+        // The corners of the rectangle around the line, half of the width away from its ends
+        let along = normalize(direction);
+        let across = (-along.1 * width / 2.0, along.0 * width / 2.0);
+        let corners = [
+            (from.0 + across.0, from.1 + across.1),
+            (to.0 + across.0, to.1 + across.1),
+            (to.0 - across.0, to.1 - across.1),
+            (from.0 - across.0, from.1 - across.1),
+        ];
+        self.polygon(&corners, Paint::Fill, color);
+    }
+
+    /// Rectangle `rect` (left, top, right, bottom)
+    pub fn rect(&mut self, rect: (f32, f32, f32, f32), paint: Paint, color: Color) {
+        if rect.0 < rect.2 && rect.1 < rect.3 {
+            self.paint(rect, paint, color, |point| rounded_rect_distance(point, rect, 0.0));
+        }
+    }
+
+    /// Rectangle `rect` (left, top, right, bottom) with the corners rounded by `radius`,
+    /// no more than half of its shortest side
+    pub fn rounded_rect(&mut self, rect: (f32, f32, f32, f32), radius: f32, paint: Paint, color: Color) {
+        if radius <= 0.0 {
+            self.rect(rect, paint, color);
+        } else if rect.0 < rect.2 && rect.1 < rect.3 {
+            self.paint(rect, paint, color, |point| rounded_rect_distance(point, rect, radius));
+        }
+    }
+
+    /// Rectangle `rect` rounded as `rounded_rect` and blurred with a gaussian of deviation `blur`, sharp
+    /// if 0. Each pixel it covers is changed with `composite(pixel, (x, y), coverage)`, to fill it with
+    /// something else than a color or to composite it in other ways than over the canvas
+    pub fn rounded_rect_with(
+        &mut self,
+        rect: (f32, f32, f32, f32),
+        radius: f32,
+        blur: f32,
+        composite: impl FnMut(&mut Argb, (i32, i32), f32),
+    ) {
+        if rect.0 < rect.2 && rect.1 < rect.3 {
+            // Farther than 3 deviations the blurred edge covers less than a level of the pixels
+            let grow = 3.0 * blur.max(0.0);
+            let bounds = (rect.0 - grow, rect.1 - grow, rect.2 + grow, rect.3 + grow);
+            self.cover(bounds, |point| blurred_coverage(rounded_rect_distance(point, rect, radius), blur), composite);
+        }
+    }
+
+    pub fn circle(&mut self, center: Point, radius: f32, paint: Paint, color: Color) {
+        self.arc(center, radius, (0.0, 360.0), paint, color);
+    }
+
+    /// Arc of the circle from the angle `start` to `end`, in degrees clockwise from the top.
+    /// Filled it's the slice of the circle between the two angles, stroked it's cut square at the ends
+    pub fn arc(&mut self, center: Point, radius: f32, (start, end): (f32, f32), paint: Paint, color: Color) {
+
+        let sweep = end - start;
+        if sweep <= 0.0 {
+            return;
+        }
+        let sector = |point: Point| sector_distance((point.0 - center.0, point.1 - center.1), start, sweep);
+
+        let grow = match paint {
+            Paint::Fill => 0.0,
+            Paint::Stroke(width) => width / 2.0,
+        };
+        let bounds = (center.0 - radius - grow, center.1 - radius - grow, center.0 + radius + grow, center.1 + radius + grow);
+        self.shape(bounds, color, |point| {
+            let circle = distance(point, center) - radius;
+            match paint {
+                Paint::Fill => circle.max(sector(point)),
+                Paint::Stroke(width) => (circle.abs() - width / 2.0).max(sector(point)),
+            }
+        });
+    }
+
+    /// Closed polygon through `points`, the inside is the one of the even-odd rule
+    pub fn polygon(&mut self, points: &[Point], paint: Paint, color: Color) {
+
+        if points.len() < 2 {
+            return;
+        }
+        let bounds = points.iter().fold((f32::MAX, f32::MAX, f32::MIN, f32::MIN), |bounds, point| {
+            (bounds.0.min(point.0), bounds.1.min(point.1), bounds.2.max(point.0), bounds.3.max(point.1))
+        });
+        self.paint(bounds, paint, color, |point| polygon_distance(point, points));
+    }
+
+    /// Fill or stroke the shape inside `bounds` (left, top, right, bottom) whose signed distance is `distance`
+    fn paint(&mut self, bounds: (f32, f32, f32, f32), paint: Paint, color: Color, distance: impl Fn(Point) -> f32) {
+        match paint {
+            Paint::Fill => self.shape(bounds, color, distance),
+            Paint::Stroke(width) => {
+                let half = width / 2.0;
+                let bounds = (bounds.0 - half, bounds.1 - half, bounds.2 + half, bounds.3 + half);
+                self.shape(bounds, color, |point| distance(point).abs() - half);
+            }
+        }
+    }
+
+    /// Composite `color` where the shape inside `bounds` (left, top, right, bottom) covers the canvas,
+    /// `distance` is the signed distance of a point from its edge, negative inside it
+    fn shape(&mut self, bounds: (f32, f32, f32, f32), color: Color, distance: impl Fn(Point) -> f32) {
+        self.cover(bounds, |point| edge_coverage(distance(point)), |pixel, _, coverage| composite::blend(pixel, color, coverage));
+    }
+
+    /// Change each pixel inside `bounds` (left, top, right, bottom) that a shape covers with
+    /// `composite(pixel, (x, y), coverage)`, `coverage` is the fraction covered of the pixel with that center
+    fn cover(&mut self, bounds: (f32, f32, f32, f32), coverage: impl Fn(Point) -> f32, mut composite: impl FnMut(&mut Argb, (i32, i32), f32)) {
+
+        // The antialiased edges reach half a pixel out of the shape
+        let x = ((bounds.0 - 1.0).floor() as i32).max(self.clip.0.start)..((bounds.2 + 1.0).ceil() as i32).min(self.clip.0.end);
+        let y = ((bounds.1 - 1.0).floor() as i32).max(self.clip.1.start)..((bounds.3 + 1.0).ceil() as i32).min(self.clip.1.end);
+
+        for y in y {
+            for x in x.clone() {
+                let coverage = coverage((x as f32 + 0.5, y as f32 + 0.5));
+                if coverage > 0.0 {
+                    if let Some(pixel) = self.pixel_mut(x, y) {
+                        composite(pixel, (x, y), coverage);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn length((x, y): (f32, f32)) -> f32 {
+    f32::sqrt(x.powf(2.0) + y.powf(2.0))
+}
+
+fn normalize((x, y): (f32, f32)) -> (f32, f32) {
+    let ln = length((x, y));
+    ((x / ln), (y / ln))
+}
+
+fn distance(a: Point, b: Point) -> f32 {
+    length((a.0 - b.0, a.1 - b.1))
+}
+
+/// Fraction of a pixel covered by a shape, from the distance of its center from the edge
+fn edge_coverage(distance: f32) -> f32 {
+    (0.5 - distance).clamp(0.0, 1.0)
+}
+
+/// Signed distance of `point` from the edge of the rectangle `rect` (left, top, right, bottom)
+/// with the corners rounded by `radius`, negative inside it
+fn rounded_rect_distance(point: Point, rect: (f32, f32, f32, f32), radius: f32) -> f32 {
+
+    let half = ((rect.2 - rect.0) / 2.0, (rect.3 - rect.1) / 2.0);
+    let radius = radius.clamp(0.0, half.0.min(half.1));
+    // Distance from the rectangle shrunk by the radius, in the quadrant of the point
+    let dx = (point.0 - (rect.0 + half.0)).abs() - half.0 + radius;
+    let dy = (point.1 - (rect.1 + half.1)).abs() - half.1 + radius;
+
+    (dx.max(0.0).powi(2) + dy.max(0.0).powi(2)).sqrt() + dx.max(dy).min(0.0) - radius
+}
+
+/// Fraction of a pixel covered by a shape blurred with a gaussian of deviation `sigma`,
+/// from the distance of its center from the edge of the sharp shape
+fn blurred_coverage(distance: f32, sigma: f32) -> f32 {
+    if sigma <= 0.0 {
+        return edge_coverage(distance);
+    }
+    0.5 * (1.0 - erf(distance / (sigma * std::f32::consts::SQRT_2)))
+}
+
+/// Error function, with the approximation 7.1.26 of Abramowitz and Stegun
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t * (0.2548296 + t * (-0.28449672 + t * (1.4214138 + t * (-1.4531521 + t * 1.0614054))));
+    (1.0 - polynomial * (-x * x).exp()).copysign(x)
+}
+
+/// Signed distance of `point`, relative to the center, from the slice of the plane
+/// `sweep` degrees wide clockwise from the angle `start`
+fn sector_distance(point: Point, start: f32, sweep: f32) -> f32 {
+
+    if sweep >= 360.0 {
+        return f32::NEG_INFINITY;
+    }
+    // With the y axis pointing down, (cos, sin) of an angle from the top is the clockwise direction
+    let (sin, cos) = start.to_radians().sin_cos();
+    let after_start = -(point.0 * cos + point.1 * sin);
+    let (sin, cos) = (start + sweep).to_radians().sin_cos();
+    let before_end = point.0 * cos + point.1 * sin;
+
+    // Up to half a turn the slice is where both sides are, above it where either is
+    if sweep <= 180.0 {
+        after_start.max(before_end)
+    } else {
+        after_start.min(before_end)
+    }
+}
+
+/// Signed distance of `point` from the edges of the polygon through `points`, negative inside it
+fn polygon_distance(point: Point, points: &[Point]) -> f32 {
+
+    let mut nearest = f32::INFINITY;
+    let mut inside = false;
+
+    for (index, a) in points.iter().enumerate() {
+        let b = points[(index + 1) % points.len()];
+        let edge = (b.0 - a.0, b.1 - a.1);
+        let to_point = (point.0 - a.0, point.1 - a.1);
+        let squared = edge.0 * edge.0 + edge.1 * edge.1;
+        let t = if squared > 0.0 { ((to_point.0 * edge.0 + to_point.1 * edge.1) / squared).clamp(0.0, 1.0) } else { 0.0 };
+        nearest = nearest.min(length((to_point.0 - edge.0 * t, to_point.1 - edge.1 * t)));
+
+        // Even-odd rule, counting the edges crossed by a ray from the point towards the right
+        if (a.1 > point.1) != (b.1 > point.1) && point.0 < a.0 + edge.0 * (point.1 - a.1) / edge.1 {
+            inside = !inside;
+        }
+    }
+
+    if inside { -nearest } else { nearest }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = Color { r: 255, g: 0, b: 0, a: 255 };
+
+    /// Alpha of the pixel at `x`, `y`
+    fn alpha(canvas: &Canvas, x: u32, y: u32) -> u8 {
+        (canvas.pixels[(x + y * canvas.width) as usize] >> 24) as u8
+    }
+
+    #[test]
+    fn filled_rect_covers_only_its_pixels() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.rect((2.0, 2.0, 8.0, 8.0), Paint::Fill, RED);
+        assert_eq!(canvas.pixels[4 + 4 * 10], 0xffff0000);
+        assert_eq!(alpha(&canvas, 2, 2), 255);
+        assert_eq!(alpha(&canvas, 7, 7), 255);
+        assert_eq!(alpha(&canvas, 1, 4), 0);
+        assert_eq!(alpha(&canvas, 8, 4), 0);
+        assert_eq!(alpha(&canvas, 0, 0), 0);
+    }
+
+    #[test]
+    fn edge_through_the_center_covers_half_a_pixel() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.rect((2.0, 2.0, 7.5, 8.0), Paint::Fill, RED);
+        assert_eq!(alpha(&canvas, 6, 4), 255);
+        assert!((127..=128).contains(&alpha(&canvas, 7, 4)), "{}", alpha(&canvas, 7, 4));
+        assert_eq!(alpha(&canvas, 8, 4), 0);
+    }
+
+    #[test]
+    fn nothing_is_drawn_outside_of_the_clip() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.set_clip(0..5, 3..20);
+        canvas.rect((0.0, 0.0, 10.0, 10.0), Paint::Fill, RED);
+        assert_eq!(alpha(&canvas, 4, 3), 255);
+        assert_eq!(alpha(&canvas, 4, 9), 255);
+        assert_eq!(alpha(&canvas, 5, 3), 0);
+        assert_eq!(alpha(&canvas, 4, 2), 0);
+
+        canvas.reset_clip();
+        canvas.rect((0.0, 0.0, 10.0, 10.0), Paint::Fill, RED);
+        assert_eq!(alpha(&canvas, 5, 2), 255);
+    }
+
+    #[test]
+    fn stroke_covers_the_outline_and_fill_the_inside() {
+        let mut stroked = Canvas::new(10, 10);
+        stroked.rect((2.0, 2.0, 8.0, 8.0), Paint::Stroke(2.0), RED);
+        let mut filled = Canvas::new(10, 10);
+        filled.rect((2.0, 2.0, 8.0, 8.0), Paint::Fill, RED);
+
+        // The stroke is centered on the edge, from 1 to 3
+        assert_eq!((alpha(&stroked, 0, 5), alpha(&stroked, 1, 5), alpha(&stroked, 2, 5), alpha(&stroked, 3, 5)), (0, 255, 255, 0));
+        assert_eq!(alpha(&stroked, 5, 5), 0);
+        assert_eq!((alpha(&filled, 1, 5), alpha(&filled, 2, 5), alpha(&filled, 5, 5)), (0, 255, 255));
+    }
+
+    #[test]
+    fn line_is_as_wide_as_set() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.line((2.0, 5.0), (8.0, 5.0), 2.0, RED);
+        assert_eq!((alpha(&canvas, 4, 3), alpha(&canvas, 4, 4), alpha(&canvas, 4, 5), alpha(&canvas, 4, 6)), (0, 255, 255, 0));
+        // Cut square at the ends
+        assert_eq!((alpha(&canvas, 1, 4), alpha(&canvas, 2, 4), alpha(&canvas, 7, 4), alpha(&canvas, 8, 4)), (0, 255, 255, 0));
+    }
+
+    #[test]
+    fn rounded_rect_with_passes_the_coverage_of_each_pixel() {
+        let mut canvas = Canvas::new(20, 10);
+        canvas.rounded_rect_with((2.0, 2.0, 7.5, 8.0), 0.0, 0.0, |pixel, (x, y), coverage| {
+            *pixel = ((coverage * 255.0).round() as u32) << 24 | (x as u32) << 8 | y as u32;
+        });
+        assert_eq!(canvas.pixels[4 + 5 * 20], 0xff000405);
+        assert_eq!(alpha(&canvas, 7, 5), 128);
+        assert_eq!(alpha(&canvas, 8, 5), 0);
+
+        // Blurred, the edge is half covered and the coverage fades away from it
+        let mut canvas = Canvas::new(20, 10);
+        canvas.rounded_rect_with((0.0, 0.0, 10.0, 10.0), 0.0, 2.0, |pixel, _, coverage| {
+            *pixel = ((coverage * 255.0).round() as u32) << 24;
+        });
+        let row: Vec<u8> = (7..15).map(|x| alpha(&canvas, x, 5)).collect();
+        assert!(row.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", row);
+        assert!((100..=155).contains(&alpha(&canvas, 10, 5)), "{:?}", row);
+    }
+
+    #[test]
+    fn circle_leaves_the_corners_out() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.circle((5.0, 5.0), 4.0, Paint::Fill, RED);
+        assert_eq!(alpha(&canvas, 4, 4), 255);
+        assert_eq!(alpha(&canvas, 2, 4), 255);
+        assert_eq!(alpha(&canvas, 1, 1), 0);
+    }
+
+    #[test]
+    fn quarter_arc_fills_one_quadrant() {
+        // Clockwise from the top: the top right quarter
+        let mut canvas = Canvas::new(20, 20);
+        canvas.arc((10.0, 10.0), 8.0, (0.0, 90.0), Paint::Fill, RED);
+        assert_eq!(alpha(&canvas, 13, 6), 255);
+        assert_eq!(alpha(&canvas, 6, 6), 0);
+        assert_eq!(alpha(&canvas, 13, 13), 0);
+        assert_eq!(alpha(&canvas, 6, 13), 0);
+        // Outside of the circle
+        assert_eq!(alpha(&canvas, 17, 3), 0);
+    }
+
+    #[test]
+    fn concave_polygon_leaves_its_notch_out() {
+        // An arrow pointing right, with a notch cut in its left side
+        let mut canvas = Canvas::new(10, 10);
+        canvas.polygon(&[(0.0, 0.0), (10.0, 5.0), (0.0, 10.0), (5.0, 5.0)], Paint::Fill, RED);
+        assert_eq!(alpha(&canvas, 3, 2), 255);
+        assert_eq!(alpha(&canvas, 7, 5), 255);
+        assert_eq!(alpha(&canvas, 2, 5), 0);
+        assert_eq!(alpha(&canvas, 9, 1), 0);
+    }
+}
@@ -0,0 +1,88 @@
+use clap::{Parser, Subcommand};
+
+use crate::parser::{Config, Position, TextAlignment, MAX_PROGRESS};
+
+/// Deamon-less notification box for Wayland compositors
+#[derive(Debug, Parser)]
+#[command(name = "gwstuff", version, about, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Name of a config in $XDG_CONFIG_HOME/gwstuff/ or $XDG_CONFIG_DIRS/gwstuff/ (without
+    /// the .toml extension), or path to a config file
+    #[arg(short, long, value_name = "NAME|PATH")]
+    pub config: Option<String>,
+
+    /// How long the box stays on screen, in milliseconds
+    #[arg(short, long, value_name = "MS")]
+    pub duration: Option<u32>,
+
+    /// Position of the box, same syntax as in the config (e.g. "top-right" or "bottom 0 5%")
+    #[arg(short, long)]
+    pub position: Option<Position>,
+
+    /// Font family used to render the text
+    #[arg(short, long, value_name = "FAMILY")]
+    pub font: Option<String>,
+
+    /// Alignment of the lines inside the box: center, left or right
+    #[arg(short, long)]
+    pub align: Option<TextAlignment>,
+
+    /// Parse the text as markup: <b>, <i>, <u> and <span color="#f00" size="20">
+    #[arg(short, long)]
+    pub markup: bool,
+
+    /// Icon shown next to the text: a PNG, JPEG or SVG file, or the name
+    /// of an icon of the icon theme (e.g. audio-volume-high)
+    #[arg(short, long, value_name = "PATH|NAME")]
+    pub icon: Option<String>,
+
+    /// Show a progress bar filled to this percentage, the part above 100 is drawn
+    /// again from the start of the bar with the overflow_color (if set)
+    #[arg(long, value_name = "0-200", value_parser = clap::value_parser!(u32).range(..=MAX_PROGRESS as i64))]
+    pub progress: Option<u32>,
+
+    /// Text to display, every argument goes on a new line
+    #[arg(required_unless_present = "progress")]
+    pub text: Vec<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check that a config is valid, without opening any window
+    CheckConfig {
+        /// Name or path of the config, the default one if not specified
+        #[arg(value_name = "NAME|PATH")]
+        config: Option<String>,
+    },
+}
+
+impl Args {
+    /// Overwrite the config fields with the ones specified on the command line
+    pub fn apply_to(&self, config: &mut Config) {
+        if let Some(duration) = self.duration {
+            config.window.duration = duration;
+        }
+        if let Some(position) = self.position {
+            config.window.position = position;
+        }
+        if let Some(font) = &self.font {
+            config.font.name = font.clone();
+            config.font.path = None;
+        }
+        if let Some(align) = self.align {
+            config.font.text_alignment = align;
+        }
+        if self.markup {
+            config.font.markup = true;
+        }
+        if let Some(icon) = &self.icon {
+            config.icon.path = Some(icon.clone());
+        }
+        if let Some(progress) = self.progress {
+            config.progress.value = Some(progress);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer, Unexpected, Visitor};
+
+/// RGBA color, the channels are not premultiplied by the alpha
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    /// Opaque color from the 0xRRGGBB integer notation
+    pub fn from_rgb_u32(rgb: u32) -> Self {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        Color { r, g, b, a: 255 }
+    }
+
+    /// Scale the alpha by an opacity percentage
+    pub fn with_opacity(self, percentage: u32) -> Self {
+        Color { a: ((self.a as u32 * percentage.min(100)) / 100) as u8, ..self }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    /// Parse "#rrggbb", "#rrggbbaa", "rgb(r,g,b)", "rgba(r,g,b,a)" or a CSS named color
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [r, g, b, a] = csscolorparser::parse(s)
+            .map_err(|err| format!("invalid color '{}': {}", s, err))?
+            .to_rgba8();
+        Ok(Color { r, g, b, a })
+    }
+}
+
+struct ColorVisitor;
+
+impl<'de> Visitor<'de> for ColorVisitor {
+    type Value = Color;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a 0xRRGGBB integer, \"#rrggbb\", \"#rrggbbaa\", \"rgba(r,g,b,a)\" or a color name")
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Color, E> {
+        match u32::try_from(value) {
+            Ok(rgb) if rgb <= 0xffffff => Ok(Color::from_rgb_u32(rgb)),
+            _ => Err(E::invalid_value(Unexpected::Signed(value), &self)),
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Color, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ColorVisitor)
+    }
+}
@@ -0,0 +1,480 @@
+use ab_glyph_rasterizer::{point as raster_point, Point as RasterPoint, Rasterizer};
+use rustybuzz::ttf_parser::colr::{ClipBox, ColorStop, CompositeMode, GradientExtend, Paint, Painter};
+use rustybuzz::ttf_parser::{self, GlyphId, OutlineBuilder, RgbaColor, Transform};
+
+use crate::color::Color;
+use crate::composite::{self, Pixel};
+use crate::glyph::ColorGlyph;
+
+/// Paint a COLR glyph: the layers of solid colors of version 0, and the gradients, transforms,
+/// clips and composite modes of version 1. The paints are evaluated at the center of each pixel
+pub fn paint_colr(face: &ttf_parser::Face, id: GlyphId, px_per_unit: f32, origin: (f32, f32), foreground: Color) -> Option<ColorGlyph> {
+
+    // The glyph is painted inside the bounding box of the whole font
+    let bbox = face.global_bounding_box();
+    let x = (origin.0 + bbox.x_min as f32 * px_per_unit).floor() as i32;
+    let y = (origin.1 - bbox.y_max as f32 * px_per_unit).floor() as i32;
+    let width = ((bbox.x_max - bbox.x_min) as f32 * px_per_unit).ceil() as u32 + 1;
+    let height = ((bbox.y_max - bbox.y_min) as f32 * px_per_unit).ceil() as u32 + 1;
+
+    // From font units (y up) to the pixels of the glyph (y down)
+    let to_pixels = Transform::new(px_per_unit, 0.0, 0.0, -px_per_unit, origin.0 - x as f32, origin.1 - y as f32);
+
+    let mut painter = LayerPainter {
+        face,
+        width: width as usize,
+        height: height as usize,
+        transforms: vec![to_pixels],
+        outline: None,
+        clips: Vec::new(),
+        layers: Vec::new(),
+        pixels: vec![[0.0; 4]; (width * height) as usize],
+    };
+
+    let foreground = RgbaColor::new(foreground.r, foreground.g, foreground.b, foreground.a);
+    face.paint_color_glyph(id, 0, foreground, &mut painter)?;
+
+    Some(ColorGlyph::from_premultiplied(x, y, width, &painter.pixels))
+}
+
+struct LayerPainter<'a> {
+    face: &'a ttf_parser::Face<'a>,
+    width: usize,
+    height: usize,
+    /// Each transform already combined with the ones below it
+    transforms: Vec<Transform>,
+    /// Coverage of the last outline, not yet used as a clip
+    outline: Option<Vec<f32>>,
+    /// Coverage of the clips, each intersected with the ones below it
+    clips: Vec<Vec<f32>>,
+    /// The layers below the one painted, each with the mode the layer above it is composited with
+    layers: Vec<(Vec<Pixel>, CompositeMode)>,
+    pixels: Vec<Pixel>,
+}
+
+impl LayerPainter<'_> {
+    fn transform(&self) -> Transform {
+        *self.transforms.last().expect("The base transform is never popped")
+    }
+
+    fn coverage(&self, draw: impl FnOnce(&mut PathRasterizer)) -> Vec<f32> {
+
+        let mut path = PathRasterizer {
+            rasterizer: Rasterizer::new(self.width, self.height),
+            transform: self.transform(),
+            start: raster_point(0.0, 0.0),
+            last: raster_point(0.0, 0.0),
+        };
+        draw(&mut path);
+
+        let mut coverage = vec![0.0; self.width * self.height];
+        path.rasterizer.for_each_pixel(|index, value| coverage[index] = value.min(1.0));
+        coverage
+    }
+
+    fn push_clip_mask(&mut self, mut mask: Vec<f32>) {
+        if let Some(clip) = self.clips.last() {
+            mask.iter_mut().zip(clip).for_each(|(value, clip)| *value *= clip);
+        }
+        self.clips.push(mask);
+    }
+}
+
+impl<'a> Painter<'a> for LayerPainter<'a> {
+    fn outline_glyph(&mut self, glyph_id: GlyphId) {
+        let face = self.face;
+        self.outline = Some(self.coverage(|path| {
+            face.outline_glyph(glyph_id, path);
+        }));
+    }
+
+    fn paint(&mut self, paint: Paint<'a>) {
+
+        // A layer of COLR v0 fills its outline, in v1 the outline is pushed as a clip before painting
+        let outline = self.outline.take();
+        let Some(fill) = Fill::new(paint, self.transform()) else {
+            return;
+        };
+
+        for (index, pixel) in self.pixels.iter_mut().enumerate() {
+            let coverage = outline.as_ref().map_or(1.0, |mask| mask[index])
+                * self.clips.last().map_or(1.0, |clip| clip[index]);
+            if coverage <= 0.0 {
+                continue;
+            }
+            let center = ((index % self.width) as f32 + 0.5, (index / self.width) as f32 + 0.5);
+            if let Some(color) = fill.color_at(center) {
+                *pixel = composite::composite_float(*pixel, color.map(|c| c * coverage), CompositeMode::SourceOver);
+            }
+        }
+    }
+
+    fn push_clip(&mut self) {
+        let mask = self.outline.take().unwrap_or_else(|| vec![0.0; self.width * self.height]);
+        self.push_clip_mask(mask);
+    }
+
+    fn push_clip_box(&mut self, clipbox: ClipBox) {
+        let mask = self.coverage(|path| {
+            path.move_to(clipbox.x_min, clipbox.y_min);
+            path.line_to(clipbox.x_max, clipbox.y_min);
+            path.line_to(clipbox.x_max, clipbox.y_max);
+            path.line_to(clipbox.x_min, clipbox.y_max);
+            path.close();
+        });
+        self.push_clip_mask(mask);
+    }
+
+    fn pop_clip(&mut self) {
+        self.clips.pop();
+    }
+
+    /// The paints that follow go on a new transparent layer, composited with `mode`
+    /// on the layer below when it's popped
+    fn push_layer(&mut self, mode: CompositeMode) {
+        let below = std::mem::replace(&mut self.pixels, vec![[0.0; 4]; self.width * self.height]);
+        self.layers.push((below, mode));
+    }
+
+    fn pop_layer(&mut self) {
+        if let Some((below, mode)) = self.layers.pop() {
+            let layer = std::mem::replace(&mut self.pixels, below);
+            self.pixels.iter_mut().zip(layer).for_each(|(dst, src)| *dst = composite::composite_float(*dst, src, mode));
+        }
+    }
+
+    fn push_transform(&mut self, transform: Transform) {
+        self.transforms.push(Transform::combine(self.transform(), transform));
+    }
+
+    fn pop_transform(&mut self) {
+        if self.transforms.len() > 1 {
+            self.transforms.pop();
+        }
+    }
+}
+
+/// A paint ready to be evaluated at the pixels of the glyph
+enum Fill {
+    Solid(Pixel),
+    Gradient {
+        shape: GradientShape,
+        /// Premultiplied colors of the color line, sorted by offset
+        stops: Vec<(f32, Pixel)>,
+        extend: GradientExtend,
+        /// From the pixels of the glyph back to the font units the gradient is defined in
+        to_font: Transform,
+    },
+}
+
+impl Fill {
+    /// `transform` maps the font units of the paint to the pixels of the glyph,
+    /// `None` if it squashes the paint into a line
+    fn new(paint: Paint, transform: Transform) -> Option<Self> {
+
+        let (shape, stops, extend) = match paint {
+            Paint::Solid(color) => return Some(Fill::Solid(premultiply(color))),
+            Paint::LinearGradient(gradient) => {
+                let shape = GradientShape::linear((gradient.x0, gradient.y0), (gradient.x1, gradient.y1), (gradient.x2, gradient.y2));
+                (shape, color_stops(gradient.stops(0, &[])), gradient.extend)
+            }
+            Paint::RadialGradient(gradient) => {
+                let shape = GradientShape::Radial {
+                    c0: (gradient.x0, gradient.y0),
+                    r0: gradient.r0,
+                    c1: (gradient.x1, gradient.y1),
+                    r1: gradient.r1,
+                };
+                (shape, color_stops(gradient.stops(0, &[])), gradient.extend)
+            }
+            Paint::SweepGradient(gradient) => {
+                // The angles are stored shifted by half a turn, in half turns
+                let shape = GradientShape::Sweep {
+                    center: (gradient.center_x, gradient.center_y),
+                    start: gradient.start_angle * 180.0 + 180.0,
+                    end: gradient.end_angle * 180.0 + 180.0,
+                };
+                (shape, color_stops(gradient.stops(0, &[])), gradient.extend)
+            }
+        };
+
+        Some(Fill::Gradient { shape, stops, extend, to_font: invert(transform)? })
+    }
+
+    /// Premultiplied color at `point` in the pixels of the glyph, `None` where nothing is painted
+    fn color_at(&self, point: (f32, f32)) -> Option<Pixel> {
+        match self {
+            Fill::Solid(color) => Some(*color),
+            Fill::Gradient { shape, stops, extend, to_font } => {
+                let point = (
+                    to_font.a * point.0 + to_font.c * point.1 + to_font.e,
+                    to_font.b * point.0 + to_font.d * point.1 + to_font.f,
+                );
+                color_line(stops, *extend, shape.offset(point, *extend)?)
+            }
+        }
+    }
+}
+
+/// Geometry of a gradient, in font units
+#[derive(Debug, PartialEq)]
+enum GradientShape {
+    /// The offset grows along `direction` from `start`, where it's 0, to `start` + `direction`, where it's 1
+    Linear { start: (f32, f32), direction: (f32, f32) },
+    /// The circles interpolated between the ones at offset 0 and 1
+    Radial { c0: (f32, f32), r0: f32, c1: (f32, f32), r1: f32 },
+    /// Angles in degrees counter-clockwise from the x axis, the offset is 0 at `start` and 1 at `end`
+    Sweep { center: (f32, f32), start: f32, end: f32 },
+}
+
+impl GradientShape {
+    /// The offset of a linear gradient from `p0` to `p1` is constant along lines parallel to
+    /// the one from `p0` to `p2`, so it's measured perpendicular to it
+    fn linear(p0: (f32, f32), p1: (f32, f32), p2: (f32, f32)) -> Self {
+
+        let to_end = (p1.0 - p0.0, p1.1 - p0.1);
+        let normal = (p0.1 - p2.1, p2.0 - p0.0);
+        let squared = dot(normal, normal);
+        let direction = if squared > 0.0 {
+            let projection = dot(to_end, normal) / squared;
+            (normal.0 * projection, normal.1 * projection)
+        } else {
+            to_end
+        };
+
+        GradientShape::Linear { start: p0, direction }
+    }
+
+    /// Offset of `point` on the color line, `None` where the gradient paints nothing
+    fn offset(&self, point: (f32, f32), extend: GradientExtend) -> Option<f32> {
+        match *self {
+            GradientShape::Linear { start, direction } => {
+                let squared = dot(direction, direction);
+                (squared > 0.0).then(|| dot((point.0 - start.0, point.1 - start.1), direction) / squared)
+            }
+            GradientShape::Radial { c0, r0, c1, r1 } => {
+                // The largest t with the point on the circle at t, of a radius that is not negative:
+                // |point - c(t)| = r(t) is a t² - 2 b t + c = 0
+                let to_c1 = (c1.0 - c0.0, c1.1 - c0.1);
+                let to_point = (point.0 - c0.0, point.1 - c0.1);
+                let dr = r1 - r0;
+                let a = dot(to_c1, to_c1) - dr * dr;
+                let b = dot(to_point, to_c1) + r0 * dr;
+                let c = dot(to_point, to_point) - r0 * r0;
+                let valid = |t: f32| r0 + t * dr >= 0.0;
+
+                if a.abs() < f32::EPSILON {
+                    let t = c / (2.0 * b);
+                    (b != 0.0 && valid(t)).then_some(t)
+                } else {
+                    let discriminant = b * b - a * c;
+                    if discriminant < 0.0 {
+                        return None;
+                    }
+                    let (t1, t2) = ((b + discriminant.sqrt()) / a, (b - discriminant.sqrt()) / a);
+                    [t1.max(t2), t1.min(t2)].into_iter().find(|t| valid(*t))
+                }
+            }
+            GradientShape::Sweep { center, start, end } => {
+                let angle = (point.1 - center.1).atan2(point.0 - center.0).to_degrees().rem_euclid(360.0);
+                if start == end {
+                    // Only padded there's a color on each side of the start
+                    return (extend == GradientExtend::Pad).then_some(if angle < start { f32::NEG_INFINITY } else { f32::INFINITY });
+                }
+                Some((angle - start) / (end - start))
+            }
+        }
+    }
+}
+
+/// Color of the color line at `offset`, interpolated between the closest `stops` (sorted by offset).
+/// Outside the stops the line is extended as `extend` says
+fn color_line(stops: &[(f32, Pixel)], extend: GradientExtend, offset: f32) -> Option<Pixel> {
+
+    let (first, last) = (stops.first()?.0, stops.last()?.0);
+    let range = last - first;
+
+    let offset = if range > 0.0 {
+        let t = (offset - first) / range;
+        let t = match extend {
+            GradientExtend::Pad => t.clamp(0.0, 1.0),
+            GradientExtend::Repeat => t - t.floor(),
+            GradientExtend::Reflect => 1.0 - (t.rem_euclid(2.0) - 1.0).abs(),
+        };
+        first + t * range
+    } else if extend == GradientExtend::Pad {
+        offset
+    } else {
+        return None;
+    };
+    if offset.is_nan() {
+        return None;
+    }
+
+    let next = stops.partition_point(|(stop, _)| *stop <= offset);
+    if next == 0 {
+        return Some(stops[0].1);
+    }
+    if next == stops.len() {
+        return Some(stops[next - 1].1);
+    }
+
+    let ((from, a), (to, b)) = (stops[next - 1], stops[next]);
+    let t = (offset - from) / (to - from);
+    Some([0, 1, 2, 3].map(|i| a[i] + (b[i] - a[i]) * t))
+}
+
+fn premultiply(color: RgbaColor) -> Pixel {
+    let alpha = color.alpha as f32 / 255.0;
+    let channel = |c: u8| c as f32 / 255.0 * alpha;
+    [channel(color.red), channel(color.green), channel(color.blue), alpha]
+}
+
+/// The premultiplied colors of a color line, sorted by offset
+fn color_stops(stops: impl Iterator<Item = ColorStop>) -> Vec<(f32, Pixel)> {
+    let mut stops: Vec<(f32, Pixel)> = stops.map(|stop| (stop.stop_offset, premultiply(stop.color))).collect();
+    stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+    stops
+}
+
+fn dot(a: (f32, f32), b: (f32, f32)) -> f32 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+/// The transform undoing `transform`, `None` if it squashes the plane into a line
+fn invert(transform: Transform) -> Option<Transform> {
+
+    let Transform { a, b, c, d, e, f } = transform;
+    let determinant = a * d - b * c;
+    if determinant == 0.0 || !determinant.is_finite() {
+        return None;
+    }
+
+    let (a, b, c, d) = (d / determinant, -b / determinant, -c / determinant, a / determinant);
+    Some(Transform::new(a, b, c, d, -(a * e + c * f), -(b * e + d * f)))
+}
+
+/// Rasterize the outline of a glyph, with its points mapped through `transform`
+struct PathRasterizer {
+    rasterizer: Rasterizer,
+    transform: Transform,
+    start: RasterPoint,
+    last: RasterPoint,
+}
+
+impl PathRasterizer {
+    fn map(&self, x: f32, y: f32) -> RasterPoint {
+        let t = &self.transform;
+        raster_point(t.a * x + t.c * y + t.e, t.b * x + t.d * y + t.f)
+    }
+}
+
+impl OutlineBuilder for PathRasterizer {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.start = self.map(x, y);
+        self.last = self.start;
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let p = self.map(x, y);
+        self.rasterizer.draw_line(self.last, p);
+        self.last = p;
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (p1, p) = (self.map(x1, y1), self.map(x, y));
+        self.rasterizer.draw_quad(self.last, p1, p);
+        self.last = p;
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (p1, p2, p) = (self.map(x1, y1), self.map(x2, y2), self.map(x, y));
+        self.rasterizer.draw_cubic(self.last, p1, p2, p);
+        self.last = p;
+    }
+
+    fn close(&mut self) {
+        if self.last != self.start {
+            self.rasterizer.draw_line(self.last, self.start);
+        }
+        self.last = self.start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Pixel = [1.0, 0.0, 0.0, 1.0];
+    const BLUE: Pixel = [0.0, 0.0, 1.0, 1.0];
+
+    fn assert_pixel(actual: Option<Pixel>, expected: Pixel) {
+        let actual = actual.expect("A color is painted");
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-4), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn color_line_interpolates_between_the_stops() {
+        let stops = [(0.0, RED), (1.0, BLUE)];
+        assert_pixel(color_line(&stops, GradientExtend::Pad, 0.25), [0.75, 0.0, 0.25, 1.0]);
+        assert_pixel(color_line(&stops, GradientExtend::Pad, -1.0), RED);
+        assert_pixel(color_line(&stops, GradientExtend::Pad, 2.0), BLUE);
+    }
+
+    #[test]
+    fn color_line_repeats_and_reflects_outside_the_stops() {
+        let stops = [(0.0, RED), (1.0, BLUE)];
+        assert_pixel(color_line(&stops, GradientExtend::Repeat, 1.25), [0.75, 0.0, 0.25, 1.0]);
+        assert_pixel(color_line(&stops, GradientExtend::Reflect, 1.25), [0.25, 0.0, 0.75, 1.0]);
+        assert_pixel(color_line(&stops, GradientExtend::Reflect, -0.25), [0.75, 0.0, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn color_line_with_the_stops_in_one_place() {
+        let stops = [(0.5, RED), (0.5, BLUE)];
+        assert_pixel(color_line(&stops, GradientExtend::Pad, 0.0), RED);
+        assert_pixel(color_line(&stops, GradientExtend::Pad, 1.0), BLUE);
+        assert_eq!(color_line(&stops, GradientExtend::Repeat, 1.0), None);
+    }
+
+    #[test]
+    fn linear_offset_is_measured_perpendicular_to_p0_p2() {
+        // p2 straight above p0: the offset grows along x only, even towards a p1 up and right
+        let shape = GradientShape::linear((0.0, 0.0), (10.0, 10.0), (0.0, 10.0));
+        assert_eq!(shape, GradientShape::Linear { start: (0.0, 0.0), direction: (10.0, 0.0) });
+        assert_eq!(shape.offset((5.0, 100.0), GradientExtend::Pad), Some(0.5));
+    }
+
+    #[test]
+    fn radial_offset_of_concentric_circles() {
+        let shape = GradientShape::Radial { c0: (0.0, 0.0), r0: 0.0, c1: (0.0, 0.0), r1: 10.0 };
+        assert_eq!(shape.offset((0.0, 5.0), GradientExtend::Pad), Some(0.5));
+        assert_eq!(shape.offset((-10.0, 0.0), GradientExtend::Pad), Some(1.0));
+    }
+
+    #[test]
+    fn radial_offset_outside_the_cone() {
+        // From a point to a circle to the right, nothing is painted behind the point
+        let shape = GradientShape::Radial { c0: (0.0, 0.0), r0: 0.0, c1: (10.0, 0.0), r1: 2.0 };
+        assert_eq!(shape.offset((-5.0, 0.0), GradientExtend::Pad), None);
+        // On two circles, the one of the larger offset is drawn over the other
+        assert_eq!(shape.offset((10.0, 0.0), GradientExtend::Pad), Some(1.25));
+    }
+
+    #[test]
+    fn sweep_offset_counter_clockwise_from_the_start() {
+        let shape = GradientShape::Sweep { center: (0.0, 0.0), start: 0.0, end: 180.0 };
+        assert_eq!(shape.offset((10.0, 0.0), GradientExtend::Pad), Some(0.0));
+        assert_eq!(shape.offset((0.0, 10.0), GradientExtend::Pad), Some(0.5));
+        assert_eq!(shape.offset((0.0, -10.0), GradientExtend::Pad), Some(1.5));
+    }
+
+    #[test]
+    fn invert_undoes_the_transform() {
+        let transform = Transform::new(2.0, 0.0, 0.0, -2.0, 10.0, 20.0);
+        let inverse = invert(transform).expect("Invertible");
+        let (x, y) = (2.0 * 3.0 + 10.0, -2.0 * 4.0 + 20.0);
+        assert_eq!((inverse.a * x + inverse.c * y + inverse.e, inverse.b * x + inverse.d * y + inverse.f), (3.0, 4.0));
+        assert!(invert(Transform::new(1.0, 1.0, 1.0, 1.0, 0.0, 0.0)).is_none());
+    }
+}
@@ -0,0 +1,371 @@
+use rustybuzz::ttf_parser::colr::CompositeMode;
+
+use crate::color::Color;
+
+/// Pixel in the wl_shm Argb8888 format: the color channels are premultiplied by the alpha
+pub type Argb = u32;
+
+/// `a * b / 255` rounded to the nearest integer, exact for every pair of bytes
+fn mul_255(a: u32, b: u32) -> u32 {
+    let product = a * b + 128;
+    (product + (product >> 8)) >> 8
+}
+
+/// The pixel of `color`, premultiplying its channels by its alpha
+pub fn premultiply(color: Color) -> Argb {
+    let a = color.a as u32;
+    (a << 24) | (mul_255(color.r as u32, a) << 16) | (mul_255(color.g as u32, a) << 8) | mul_255(color.b as u32, a)
+}
+
+/// Premultiplied pixel with every channel (alpha included) scaled by `coverage`, from 0.0 to 1.0
+pub fn scale(pixel: Argb, coverage: f32) -> Argb {
+    let coverage = (coverage.clamp(0.0, 1.0) * 255.0).round() as u32;
+    u32::from_be_bytes(pixel.to_be_bytes().map(|c| mul_255(c as u32, coverage) as u8))
+}
+
+/// Sum of two premultiplied pixels covering parts of the same pixel that don't overlap
+pub fn add(a: Argb, b: Argb) -> Argb {
+    let [a, b] = [a, b].map(u32::to_be_bytes);
+    u32::from_be_bytes([0, 1, 2, 3].map(|i| a[i].saturating_add(b[i])))
+}
+
+/// Source over of two premultiplied pixels: `src` drawn on top of `dst`
+pub fn over(dst: Argb, src: Argb) -> Argb {
+    let [sa, sr, sg, sb] = src.to_be_bytes();
+    let [da, dr, dg, db] = dst.to_be_bytes();
+    let transparency = 255 - sa as u32;
+    // Premultiplied channels never exceed the alpha, so the sum fits in a byte
+    let channel = |s: u8, d: u8| (s as u32 + mul_255(d as u32, transparency)) as u8;
+    u32::from_be_bytes([channel(sa, da), channel(sr, dr), channel(sg, dg), channel(sb, db)])
+}
+
+/// Composite `color` over `dst` where a shape covers the fraction `coverage` of the pixel
+pub fn blend(dst: &mut Argb, color: Color, coverage: f32) {
+    *dst = over(*dst, scale(premultiply(color), coverage));
+}
+
+/// Composite `color` over `dst` as `blend`, but mixing the colors in linear light.
+/// The antialiased edges keep the brightness of a color halfway between the two,
+/// instead of the darker one of the average of their sRGB values
+pub fn blend_linear(dst: &mut Argb, color: Color, coverage: f32) {
+
+    let alpha = color.a as f32 / 255.0 * coverage.clamp(0.0, 1.0);
+    if alpha <= 0.0 {
+        return;
+    }
+
+    let [da, dr, dg, db] = dst.to_be_bytes().map(|c| c as f32 / 255.0);
+    let out_a = alpha + da * (1.0 - alpha);
+    let out_a_byte = (out_a * 255.0).round() as u32;
+    // Mixed as straight linear colors, then encoded and premultiplied as `premultiply` does
+    let channel = |s: u8, d: f32| {
+        let d = if da > 0.0 { to_linear(d / da) } else { 0.0 };
+        let mixed = (to_linear(s as f32 / 255.0) * alpha + d * da * (1.0 - alpha)) / out_a;
+        mul_255((to_srgb(mixed) * 255.0).round() as u32, out_a_byte) as u8
+    };
+
+    *dst = u32::from_be_bytes([out_a_byte as u8, channel(color.r, dr), channel(color.g, dg), channel(color.b, db)]);
+}
+
+/// sRGB encoded channel to linear light, both from 0.0 to 1.0
+fn to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Linear light channel to sRGB encoded, both from 0.0 to 1.0
+fn to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Premultiplied RGBA in the range 0.0 to 1.0, the pixels of the color glyphs while they are built.
+/// Their layers are composited over each other with all the modes of the COLR table: in bytes each
+/// step would round the colors again, and the blend modes need the straight colors that the few
+/// levels of the translucent premultiplied bytes no longer hold. A finished glyph is converted
+/// with `to_argb` and drawn with `over` like everything else
+pub type Pixel = [f32; 4];
+
+/// The `Argb` pixel of a float one
+pub fn to_argb([r, g, b, a]: Pixel) -> Argb {
+    // Rounding may push a channel over the alpha
+    let alpha = (a * 255.0).round().clamp(0.0, 255.0) as u8;
+    let channel = |c: f32| ((c * 255.0).round().clamp(0.0, 255.0) as u8).min(alpha);
+    u32::from_be_bytes([alpha, channel(r), channel(g), channel(b)])
+}
+
+/// Composite the premultiplied `src` on `dst` with `mode`: the Porter-Duff operators
+/// and the blend modes of the W3C Compositing and Blending spec. `SourceOver` is `over` in floats
+pub fn composite_float(dst: Pixel, src: Pixel, mode: CompositeMode) -> Pixel {
+
+    let (sa, da) = (src[3], dst[3]);
+    let porter_duff = |fa: f32, fb: f32| [0, 1, 2, 3].map(|i| src[i] * fa + dst[i] * fb);
+
+    match mode {
+        CompositeMode::Clear => [0.0; 4],
+        CompositeMode::Source => src,
+        CompositeMode::Destination => dst,
+        CompositeMode::SourceOver => porter_duff(1.0, 1.0 - sa),
+        CompositeMode::DestinationOver => porter_duff(1.0 - da, 1.0),
+        CompositeMode::SourceIn => porter_duff(da, 0.0),
+        CompositeMode::DestinationIn => porter_duff(0.0, sa),
+        CompositeMode::SourceOut => porter_duff(1.0 - da, 0.0),
+        CompositeMode::DestinationOut => porter_duff(0.0, 1.0 - sa),
+        CompositeMode::SourceAtop => porter_duff(da, 1.0 - sa),
+        CompositeMode::DestinationAtop => porter_duff(1.0 - da, sa),
+        CompositeMode::Xor => porter_duff(1.0 - da, 1.0 - sa),
+        CompositeMode::Plus => porter_duff(1.0, 1.0).map(|c| c.min(1.0)),
+        _ => {
+            // The blended color is mixed with the ones of the layers where they both have color
+            let straight = |pixel: Pixel| if pixel[3] > 0.0 { [0, 1, 2].map(|i| pixel[i] / pixel[3]) } else { [0.0; 3] };
+            let (cs, cd) = (straight(src), straight(dst));
+            let blended = match mode {
+                CompositeMode::Hue => set_lum(set_sat(cs, sat(cd)), lum(cd)),
+                CompositeMode::Saturation => set_lum(set_sat(cd, sat(cs)), lum(cd)),
+                CompositeMode::Color => set_lum(cs, lum(cd)),
+                CompositeMode::Luminosity => set_lum(cd, lum(cs)),
+                _ => [0, 1, 2].map(|i| blend_channel(mode, cs[i], cd[i])),
+            };
+            let channel = |i: usize| (1.0 - da) * src[i] + (1.0 - sa) * dst[i] + sa * da * blended[i];
+            [channel(0), channel(1), channel(2), sa + da - sa * da]
+        }
+    }
+}
+
+/// Separable blend mode of the straight channels `s` (source) and `d` (destination)
+fn blend_channel(mode: CompositeMode, s: f32, d: f32) -> f32 {
+
+    let screen = |s: f32, d: f32| s + d - s * d;
+    let hard_light = |s: f32, d: f32| if s <= 0.5 { d * 2.0 * s } else { screen(d, 2.0 * s - 1.0) };
+
+    match mode {
+        CompositeMode::Multiply => s * d,
+        CompositeMode::Screen => screen(s, d),
+        CompositeMode::Overlay => hard_light(d, s),
+        CompositeMode::Darken => s.min(d),
+        CompositeMode::Lighten => s.max(d),
+        CompositeMode::ColorDodge if d <= 0.0 => 0.0,
+        CompositeMode::ColorDodge if s >= 1.0 => 1.0,
+        CompositeMode::ColorDodge => (d / (1.0 - s)).min(1.0),
+        CompositeMode::ColorBurn if d >= 1.0 => 1.0,
+        CompositeMode::ColorBurn if s <= 0.0 => 0.0,
+        CompositeMode::ColorBurn => 1.0 - ((1.0 - d) / s).min(1.0),
+        CompositeMode::HardLight => hard_light(s, d),
+        CompositeMode::SoftLight if s <= 0.5 => d - (1.0 - 2.0 * s) * d * (1.0 - d),
+        CompositeMode::SoftLight => {
+            let darkened = if d <= 0.25 { ((16.0 * d - 12.0) * d + 4.0) * d } else { d.sqrt() };
+            d + (2.0 * s - 1.0) * (darkened - d)
+        }
+        CompositeMode::Difference => (s - d).abs(),
+        CompositeMode::Exclusion => s + d - 2.0 * s * d,
+        _ => s,
+    }
+}
+
+fn lum(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+fn sat(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+/// `c` with the luminosity `l`, its channels brought back in range keeping the luminosity
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3] {
+
+    let shift = l - lum(c);
+    let c = c.map(|channel| channel + shift);
+
+    let (min, max) = (c[0].min(c[1]).min(c[2]), c[0].max(c[1]).max(c[2]));
+    if min < 0.0 {
+        c.map(|channel| l + (channel - l) * l / (l - min))
+    } else if max > 1.0 {
+        c.map(|channel| l + (channel - l) * (1.0 - l) / (max - l))
+    } else {
+        c
+    }
+}
+
+/// `c` with the saturation `s`, keeping the order of its channels
+fn set_sat(c: [f32; 3], s: f32) -> [f32; 3] {
+    let (min, max) = (c[0].min(c[1]).min(c[2]), c[0].max(c[1]).max(c[2]));
+    if max > min {
+        c.map(|channel| (channel - min) * s / (max - min))
+    } else {
+        [0.0; 3]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Color of a premultiplied pixel, the channels of a fully transparent one are black
+    fn unpremultiply(pixel: Argb) -> Color {
+        let [a, r, g, b] = pixel.to_be_bytes();
+        let channel = |c: u8| if a == 0 { 0 } else { ((c as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8 };
+        Color { r: channel(r), g: channel(g), b: channel(b), a }
+    }
+
+    #[test]
+    fn mul_255_is_exact() {
+        for a in 0..=255 {
+            for b in 0..=255 {
+                assert_eq!(mul_255(a, b), ((a * b) as f32 / 255.0).round() as u32, "{} * {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn premultiply_scales_the_channels() {
+        assert_eq!(premultiply(Color { r: 255, g: 128, b: 0, a: 255 }), 0xffff8000);
+        assert_eq!(premultiply(Color { r: 255, g: 128, b: 0, a: 128 }), 0x80804000);
+        assert_eq!(premultiply(Color { r: 255, g: 255, b: 255, a: 0 }), 0x00000000);
+    }
+
+    #[test]
+    fn unpremultiply_restores_the_color() {
+        assert_eq!(unpremultiply(0x80804000), Color { r: 255, g: 128, b: 0, a: 128 });
+        assert_eq!(unpremultiply(0x00000000), Color { r: 0, g: 0, b: 0, a: 0 });
+    }
+
+    #[test]
+    fn opaque_source_replaces_destination() {
+        assert_eq!(over(0xff123456, 0xffabcdef), 0xffabcdef);
+        assert_eq!(over(0x80402010, 0xff000000), 0xff000000);
+    }
+
+    #[test]
+    fn transparent_source_keeps_destination() {
+        assert_eq!(over(0xff123456, 0x00000000), 0xff123456);
+        assert_eq!(over(0x80402010, 0x00000000), 0x80402010);
+    }
+
+    #[test]
+    fn half_white_over_opaque_black() {
+        assert_eq!(over(0xff000000, 0x80808080), 0xff808080);
+    }
+
+    #[test]
+    fn half_red_over_transparent() {
+        // Over nothing the source is left as it is
+        assert_eq!(over(0x00000000, 0x80800000), 0x80800000);
+    }
+
+    #[test]
+    fn translucent_over_translucent() {
+        // Half blue over half red: alpha 0.5 + 0.5 * 0.5, red 0.5 * 0.5, blue 0.5
+        assert_eq!(over(0x80800000, 0x80000080), 0xc0400080);
+    }
+
+    #[test]
+    fn antialiased_edge_on_translucent_background() {
+        // Half covered white text on a half transparent black background
+        let mut pixel = premultiply(Color { r: 0, g: 0, b: 0, a: 128 });
+        blend(&mut pixel, Color { r: 255, g: 255, b: 255, a: 255 }, 0.5);
+        assert_eq!(pixel, 0xc0808080);
+        assert_eq!(unpremultiply(pixel), Color { r: 170, g: 170, b: 170, a: 192 });
+    }
+
+    #[test]
+    fn coverage_scales_alpha_too() {
+        assert_eq!(scale(0xff804020, 0.5), 0x80402010);
+        assert_eq!(scale(0xff804020, 0.0), 0x00000000);
+        assert_eq!(scale(0xff804020, 1.0), 0xff804020);
+    }
+
+    #[test]
+    fn blend_with_translucent_color() {
+        let mut pixel = 0xffffffff;
+        blend(&mut pixel, Color { r: 0, g: 0, b: 0, a: 128 }, 1.0);
+        assert_eq!(pixel, 0xff7f7f7f);
+    }
+
+    #[test]
+    fn add_of_complementary_coverages() {
+        // A pixel half covered by the background and half by the border is opaque
+        let background = scale(0xff000080, 0.5);
+        let border = scale(0xffff0000, 0.5);
+        assert_eq!(add(background, border), 0xff800040);
+        assert_eq!(add(0xffffffff, 0xffffffff), 0xffffffff);
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        for c in 0..=255u8 {
+            assert_eq!((to_srgb(to_linear(c as f32 / 255.0)) * 255.0).round() as u8, c);
+        }
+    }
+
+    #[test]
+    fn linear_blend_of_full_coverage_is_plain_over() {
+        let mut linear = 0xff204060;
+        let mut plain = 0xff204060;
+        blend_linear(&mut linear, Color { r: 200, g: 100, b: 50, a: 255 }, 1.0);
+        blend(&mut plain, Color { r: 200, g: 100, b: 50, a: 255 }, 1.0);
+        assert_eq!(linear, plain);
+    }
+
+    #[test]
+    fn linear_blend_half_white_on_black() {
+        // Half of the light of white is 0.5 in linear light, 188 in sRGB
+        let mut pixel = 0xff000000;
+        blend_linear(&mut pixel, Color { r: 255, g: 255, b: 255, a: 255 }, 0.5);
+        assert_eq!(pixel, 0xffbcbcbc);
+    }
+
+    #[test]
+    fn linear_blend_half_black_on_white() {
+        let mut pixel = 0xffffffff;
+        blend_linear(&mut pixel, Color { r: 0, g: 0, b: 0, a: 255 }, 0.5);
+        assert_eq!(pixel, 0xffbcbcbc);
+    }
+
+    #[test]
+    fn linear_blend_on_transparent() {
+        // With nothing below only the alpha is scaled
+        let mut pixel = 0x00000000;
+        blend_linear(&mut pixel, Color { r: 255, g: 0, b: 0, a: 255 }, 0.5);
+        assert_eq!(pixel, 0x80800000);
+    }
+
+    const RED: Pixel = [1.0, 0.0, 0.0, 1.0];
+    const BLUE: Pixel = [0.0, 0.0, 1.0, 1.0];
+
+    fn assert_pixel(actual: Pixel, expected: Pixel) {
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-4), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn over_is_source_over_in_floats() {
+        let to_float = |pixel: Argb| {
+            let [a, r, g, b] = pixel.to_be_bytes().map(|c| c as f32 / 255.0);
+            [r, g, b, a]
+        };
+        for (dst, src) in [(0xff204060, 0x80400000), (0x80808080, 0x40102030), (0x00000000, 0xc0c0c0c0)] {
+            let float = composite_float(to_float(dst), to_float(src), CompositeMode::SourceOver);
+            assert_eq!(over(dst, src), to_argb(float), "{:08x} over {:08x}", src, dst);
+        }
+    }
+
+    #[test]
+    fn porter_duff_operators() {
+        let half_red = RED.map(|c| c * 0.5);
+        assert_pixel(composite_float(BLUE, half_red, CompositeMode::SourceOver), [0.5, 0.0, 0.5, 1.0]);
+        assert_pixel(composite_float([0.0; 4], RED, CompositeMode::SourceIn), [0.0; 4]);
+        assert_pixel(composite_float(BLUE, half_red, CompositeMode::DestinationOut), [0.0, 0.0, 0.5, 0.5]);
+        assert_pixel(composite_float(BLUE, RED, CompositeMode::Xor), [0.0; 4]);
+    }
+
+    #[test]
+    fn blend_modes_mix_the_colors() {
+        let gray = [0.5, 0.5, 0.5, 1.0];
+        assert_pixel(composite_float(gray, gray, CompositeMode::Multiply), [0.25, 0.25, 0.25, 1.0]);
+        assert_pixel(composite_float(gray, gray, CompositeMode::Screen), [0.75, 0.75, 0.75, 1.0]);
+        assert_pixel(composite_float(BLUE, RED, CompositeMode::Difference), [1.0, 0.0, 1.0, 1.0]);
+        // Over a transparent layer a blend mode is source over
+        assert_pixel(composite_float([0.0; 4], RED, CompositeMode::Multiply), RED);
+        // The luminosity of the source with the hue and saturation of the destination
+        let blended = composite_float(RED, [1.0, 1.0, 1.0, 1.0], CompositeMode::Luminosity);
+        assert_pixel(blended, [1.0, 1.0, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,505 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{c_char, c_int, CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{fs, io, ptr};
+
+use fontconfig::fontconfig::{
+    FcChar8, FcCharSetAddChar, FcCharSetCreate, FcCharSetDestroy, FcConfigSubstitute, FcDefaultSubstitute,
+    FcFontList, FcFontMatch, FcFontSetDestroy, FcMatchPattern, FcObjectSetAdd, FcObjectSetCreate, FcObjectSetDestroy,
+    FcPattern, FcPatternAddBool, FcPatternAddCharSet, FcPatternAddInteger, FcPatternAddString, FcPatternCreate,
+    FcPatternDestroy, FcPatternGetInteger, FcPatternGetString, FcResultMatch, FcResultNoMatch,
+};
+use rusttype::{Font, Scale, VMetrics};
+use thiserror::Error;
+use unicode_properties::emoji::{EmojiStatus, UnicodeEmoji};
+
+use crate::parser::{expand_home, FontProps, FontStretch, FontStyle, FontWeight};
+
+#[derive(Debug, Error)]
+pub enum FontError {
+    #[error("font '{name}' not found, {}", suggest(close))]
+    NotFound { name: String, close: Vec<String> },
+
+    #[error("cannot read the font {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{} is not a valid font file", path.display())]
+    Invalid { path: PathBuf },
+}
+
+fn suggest(close: &[String]) -> String {
+    if close.is_empty() {
+        "the installed families are listed by `fc-list : family`".to_string()
+    } else {
+        format!("close matches: {}", close.iter().map(|name| format!("'{}'", name)).collect::<Vec<String>>().join(", "))
+    }
+}
+
+/// A font of the system, parsed once for the rasterizer and for the shaper
+pub struct FontFace {
+    face: rustybuzz::Face<'static>,
+    /// The font has glyphs in their own colors: COLR layers or color bitmaps
+    color: bool,
+    pub font: Font<'static>,
+}
+
+impl FontFace {
+    /// Ask fontconfig for the font of `family` closest to the requested weight, style and stretch.
+    /// Unless `family` is a generic one, a font of another family is not accepted as a match
+    pub fn find(family: &str, weight: FontWeight, style: FontStyle, stretch: FontStretch) -> Result<Self, FontError> {
+
+        let not_found = || FontError::NotFound { name: family.to_string(), close: close_families(family) };
+        let family_c = CString::new(family).map_err(|_| not_found())?;
+
+        let matched = match_font(|pattern| unsafe {
+            FcPatternAddString(pattern, FC_FAMILY.as_ptr() as *const c_char, family_c.as_ptr() as *const FcChar8);
+            FcPatternAddInteger(pattern, FC_WEIGHT.as_ptr() as *const c_char, fc_weight(weight));
+            FcPatternAddInteger(pattern, FC_SLANT.as_ptr() as *const c_char, fc_slant(style));
+            FcPatternAddInteger(pattern, FC_WIDTH.as_ptr() as *const c_char, stretch.percentage() as c_int);
+        })
+        .ok_or_else(not_found)?;
+
+        // fontconfig always matches some font, even of an unrelated family
+        let generic = GENERIC_FAMILIES.iter().any(|generic| generic.eq_ignore_ascii_case(family));
+        if !generic && !matched.families.iter().any(|name| name.eq_ignore_ascii_case(family)) {
+            return Err(not_found());
+        }
+
+        FontFace::open(&matched.path, matched.index)
+    }
+
+    /// Load the face at `index` of the font file at `path`
+    pub fn open(path: &Path, index: u32) -> Result<Self, FontError> {
+        let data = fs::read(path).map_err(|source| FontError::Io { path: path.to_path_buf(), source })?;
+        FontFace::from_data(data, index).ok_or_else(|| FontError::Invalid { path: path.to_path_buf() })
+    }
+
+    fn from_data(data: Vec<u8>, index: u32) -> Option<Self> {
+        // The fonts are used until the program exits, their data is never freed
+        // so that the parsed faces can borrow it
+        let data: &'static [u8] = Box::leak(data.into_boxed_slice());
+        let font = Font::try_from_bytes_and_index(data, index)?;
+
+        let face = rustybuzz::Face::from_slice(data, index)?;
+        let tables = face.tables();
+        let color = tables.colr.is_some() || tables.cbdt.is_some() || tables.sbix.is_some();
+
+        Some(FontFace { face, color, font })
+    }
+
+    /// Face used to shape the text with this font
+    pub fn shaping_face(&self) -> &rustybuzz::Face<'static> {
+        &self.face
+    }
+
+    /// Pixels per font unit with `scale`, the same factor used by rusttype to rasterize
+    pub fn px_per_unit(&self, scale: Scale) -> f32 {
+        self.font.scale_for_pixel_height(scale.x)
+    }
+
+    pub fn v_metrics(&self, scale: Scale) -> VMetrics {
+        self.font.v_metrics(scale)
+    }
+
+    /// Distance of the underline from the baseline (positive below it) and its thickness, in pixels
+    pub fn underline(&self, scale: Scale) -> (f32, f32) {
+
+        let px_per_unit = self.px_per_unit(scale);
+        let face = self.shaping_face();
+        let units_per_em = face.units_per_em() as f32;
+
+        match face.underline_metrics() {
+            Some(metrics) if metrics.thickness > 0 => (-metrics.position as f32 * px_per_unit, metrics.thickness as f32 * px_per_unit),
+            _ => (units_per_em / 10.0 * px_per_unit, units_per_em / 14.0 * px_per_unit),
+        }
+    }
+
+    pub fn is_color(&self) -> bool {
+        self.color
+    }
+
+    /// The font has a glyph for every character of `cluster`, the invisible
+    /// formatting characters are left to the shaper and not required
+    pub fn covers(&self, cluster: &str) -> bool {
+        cluster.chars().filter(|c| !is_default_ignorable(*c)).all(|c| self.font.glyph(c).id().0 != 0)
+    }
+}
+
+/// Characters with no visible glyph that a font may lack without showing a missing glyph box
+fn is_default_ignorable(c: char) -> bool {
+    matches!(c,
+        '\u{00AD}' | '\u{034F}' | '\u{061C}' | '\u{180B}'..='\u{180F}' | '\u{200B}'..='\u{200F}' |
+        '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{206F}' | '\u{FE00}'..='\u{FE0F}' | '\u{FEFF}' |
+        '\u{E0000}'..='\u{E0FFF}'
+    )
+}
+
+/// The cluster is shown as an emoji by default, or asks to be with the emoji variation selector
+fn is_emoji_presentation(cluster: &str) -> bool {
+
+    if cluster.contains('\u{FE0F}') {
+        return true;
+    }
+    if cluster.contains('\u{FE0E}') {
+        return false;
+    }
+    // Emoji_Presentation of the Unicode emoji data
+    cluster.chars().next().is_some_and(|c| matches!(c.emoji_status(),
+        EmojiStatus::EmojiPresentation | EmojiStatus::EmojiPresentationAndModifierBase |
+        EmojiStatus::EmojiPresentationAndEmojiComponent | EmojiStatus::EmojiPresentationAndModifierAndEmojiComponent
+    ))
+}
+
+/// Variant of the configured font, chosen by the markup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FontVariant {
+    pub bold: bool,
+    pub italic: bool,
+}
+
+/// The configured font followed by its fallbacks, the text is rendered with the first one
+/// that has the glyphs it needs. The fonts found by fontconfig for the characters missing
+/// in all of them are appended while the text is laid out
+pub struct FontChain {
+    faces: RefCell<Vec<Rc<FontFace>>>,
+    /// Characters fontconfig was already asked for, with or without requiring
+    /// a color font, not to search them again
+    searched: RefCell<HashSet<(char, bool)>>,
+}
+
+impl FontChain {
+    /// Load the fonts of `props` in `variant`: bold is at least 700 and italic
+    /// is used if the configured style is normal. A font given by its `path`
+    /// is used as it is in every variant
+    pub fn load(props: &FontProps, variant: FontVariant) -> Result<Self, FontError> {
+
+        let weight = if variant.bold { FontWeight(props.weight.0.max(700)) } else { props.weight };
+        let style = if variant.italic && props.style == FontStyle::Normal { FontStyle::Italic } else { props.style };
+
+        let mut faces = vec![Rc::new(match &props.path {
+            Some(path) => FontFace::open(&expand_home(path), 0)?,
+            None => FontFace::find(&props.name, weight, style, props.stretch)?,
+        })];
+        // Only the configured font is required, a missing fallback is skipped
+        for family in props.fallback.iter() {
+            match FontFace::find(family, weight, style, props.stretch) {
+                Ok(face) => faces.push(Rc::new(face)),
+                // Reported once, when the regular fonts are loaded
+                Err(err) if variant == FontVariant::default() => eprintln!("Warning: fallback {}, skipped", err),
+                Err(_) => (),
+            }
+        }
+
+        Ok(FontChain {
+            faces: RefCell::new(faces),
+            searched: RefCell::new(HashSet::new()),
+        })
+    }
+
+    /// The configured font, used for the metrics of the text
+    pub fn primary(&self) -> Rc<FontFace> {
+        self.face(0)
+    }
+
+    pub fn face(&self, index: usize) -> Rc<FontFace> {
+        self.faces.borrow()[index].clone()
+    }
+
+    /// Index of the font used to render `cluster` (a single grapheme cluster), emoji
+    /// are rendered with a color font if there is one that has them.
+    /// `current` is kept if it covers the cluster, not to split a run on spaces and punctuation.
+    /// When no loaded font has its glyphs fontconfig is asked for one, falling back
+    /// to the configured font (and its missing glyph box) if there is none
+    pub fn font_for(&self, cluster: &str, current: Option<usize>) -> usize {
+
+        if is_emoji_presentation(cluster) {
+            if let Some(index) = self.find_font(cluster, current, true) {
+                return index;
+            }
+        }
+
+        self.find_font(cluster, current, false).unwrap_or(0)
+    }
+
+    fn find_font(&self, cluster: &str, current: Option<usize>, color: bool) -> Option<usize> {
+
+        let usable = |face: &FontFace| face.covers(cluster) && (!color || face.is_color());
+
+        // The text around an emoji does not stay in the emoji font
+        if let Some(current) = current {
+            let face = self.face(current);
+            if usable(&face) && (color || !face.is_color()) {
+                return Some(current);
+            }
+        }
+
+        if let Some(index) = self.faces.borrow().iter().position(|face| usable(face)) {
+            return Some(index);
+        }
+
+        for c in cluster.chars().filter(|c| !is_default_ignorable(*c)) {
+            if !self.searched.borrow_mut().insert((c, color)) {
+                continue;
+            }
+
+            if let Some(face) = find_system_font(c, color) {
+                let fits = usable(&face);
+                let mut faces = self.faces.borrow_mut();
+                faces.push(Rc::new(face));
+                if fits {
+                    return Some(faces.len() - 1);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// The font chains of the configured font in the variants used by the text,
+/// the regular one is loaded upfront and the others the first time they're needed
+pub struct Fonts {
+    props: FontProps,
+    chains: RefCell<HashMap<FontVariant, Rc<FontChain>>>,
+}
+
+impl Fonts {
+    /// Load the configured fonts, failing if the primary one is missing
+    pub fn new(props: &FontProps) -> Result<Self, FontError> {
+        let regular = FontChain::load(props, FontVariant::default())?;
+        let chains = HashMap::from([(FontVariant::default(), Rc::new(regular))]);
+        Ok(Fonts { props: props.clone(), chains: RefCell::new(chains) })
+    }
+
+    /// The fonts of `variant`, the regular ones if the family has no such variant
+    pub fn chain(&self, variant: FontVariant) -> Rc<FontChain> {
+
+        if let Some(chain) = self.chains.borrow().get(&variant) {
+            return chain.clone();
+        }
+
+        let chain = match FontChain::load(&self.props, variant) {
+            Ok(chain) => Rc::new(chain),
+            Err(_) => self.chain(FontVariant::default()),
+        };
+        self.chains.borrow_mut().insert(variant, chain.clone());
+        chain
+    }
+
+    /// The configured font in its regular variant, used for the metrics of the text
+    pub fn primary(&self) -> Rc<FontFace> {
+        self.chain(FontVariant::default()).primary()
+    }
+}
+
+/// Generic families fontconfig resolves to the preferred font of the system
+static GENERIC_FAMILIES: &[&str] = &["sans-serif", "sans", "serif", "monospace", "mono", "cursive", "fantasy", "system-ui", "emoji", "math"];
+
+static FC_FAMILY: &[u8] = b"family\0";
+static FC_WEIGHT: &[u8] = b"weight\0";
+static FC_SLANT: &[u8] = b"slant\0";
+static FC_WIDTH: &[u8] = b"width\0";
+static FC_CHARSET: &[u8] = b"charset\0";
+static FC_FILE: &[u8] = b"file\0";
+static FC_INDEX: &[u8] = b"index\0";
+static FC_COLOR: &[u8] = b"color\0";
+
+// Not bound by the fontconfig crate, available since fontconfig 2.11.91
+extern "C" {
+    fn FcWeightFromOpenType(ot_weight: c_int) -> c_int;
+}
+
+/// fontconfig weight of a CSS (OpenType) one
+fn fc_weight(weight: FontWeight) -> c_int {
+    unsafe { FcWeightFromOpenType(weight.0 as c_int) }
+}
+
+fn fc_slant(style: FontStyle) -> c_int {
+    match style {
+        FontStyle::Normal => 0,
+        FontStyle::Italic => 100,
+        FontStyle::Oblique => 110,
+    }
+}
+
+/// Ask fontconfig for the best font with a glyph for `c`, a color font if `color`
+fn find_system_font(c: char, color: bool) -> Option<FontFace> {
+
+    let matched = match_font(|pattern| unsafe {
+        if color {
+            FcPatternAddBool(pattern, FC_COLOR.as_ptr() as *const c_char, 1);
+        }
+        let charset = FcCharSetCreate();
+        FcCharSetAddChar(charset, c as u32);
+        // The pattern keeps its own reference to the charset
+        FcPatternAddCharSet(pattern, FC_CHARSET.as_ptr() as *const c_char, charset);
+        FcCharSetDestroy(charset);
+    })?;
+    let face = FontFace::open(&matched.path, matched.index).ok()?;
+
+    // The best match is not guaranteed to have the character
+    if face.covers(&c.to_string()) && (!color || face.is_color()) { Some(face) } else { None }
+}
+
+/// A font matched by fontconfig
+struct MatchedFont {
+    path: PathBuf,
+    index: u32,
+    families: Vec<String>,
+}
+
+/// The font fontconfig matches to the pattern filled by `setup`,
+/// after completing it with the configured and the default values
+fn match_font(setup: impl FnOnce(*mut FcPattern)) -> Option<MatchedFont> {
+    unsafe {
+        let pattern = FcPatternCreate();
+        setup(pattern);
+
+        FcConfigSubstitute(ptr::null_mut(), pattern, FcMatchPattern);
+        FcDefaultSubstitute(pattern);
+
+        let mut result = FcResultNoMatch;
+        let matched = FcFontMatch(ptr::null_mut(), pattern, &mut result);
+        FcPatternDestroy(pattern);
+        if matched.is_null() {
+            return None;
+        }
+
+        let mut file: *mut FcChar8 = ptr::null_mut();
+        let mut index: c_int = 0;
+        let found = if FcPatternGetString(matched, FC_FILE.as_ptr() as *const c_char, 0, &mut file) == FcResultMatch {
+            let path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(file as *const c_char).to_bytes()));
+            if FcPatternGetInteger(matched, FC_INDEX.as_ptr() as *const c_char, 0, &mut index) != FcResultMatch {
+                index = 0;
+            }
+            Some(MatchedFont { path, index: index.max(0) as u32, families: pattern_strings(matched, FC_FAMILY) })
+        } else {
+            None
+        };
+
+        FcPatternDestroy(matched);
+        found
+    }
+}
+
+/// All the values of the string property `object` of `pattern`
+unsafe fn pattern_strings(pattern: *mut FcPattern, object: &[u8]) -> Vec<String> {
+
+    let mut values = Vec::new();
+    let mut value: *mut FcChar8 = ptr::null_mut();
+    while FcPatternGetString(pattern, object.as_ptr() as *const c_char, values.len() as c_int, &mut value) == FcResultMatch {
+        values.push(CStr::from_ptr(value as *const c_char).to_string_lossy().into_owned());
+    }
+    values
+}
+
+/// Families of the installed fonts
+fn installed_families() -> Vec<String> {
+    unsafe {
+        let pattern = FcPatternCreate();
+        let objects = FcObjectSetCreate();
+        FcObjectSetAdd(objects, FC_FAMILY.as_ptr() as *const c_char);
+        let set = FcFontList(ptr::null_mut(), pattern, objects);
+        FcObjectSetDestroy(objects);
+        FcPatternDestroy(pattern);
+        if set.is_null() {
+            return Vec::new();
+        }
+
+        let mut families: Vec<String> = (0..(*set).nfont.max(0) as usize)
+            .flat_map(|i| pattern_strings(*(*set).fonts.add(i), FC_FAMILY))
+            .collect();
+        FcFontSetDestroy(set);
+
+        families.sort();
+        families.dedup();
+        families
+    }
+}
+
+/// The installed families whose name is similar to `family`, the closest first
+fn close_families(family: &str) -> Vec<String> {
+
+    let family = family.to_lowercase();
+    let max_distance = (family.chars().count() / 3).max(2);
+
+    let mut close: Vec<(usize, String)> = installed_families()
+        .into_iter()
+        .filter_map(|name| {
+            let lower = name.to_lowercase();
+            let distance = edit_distance(&family, &lower);
+            let contained = lower.contains(&family) || family.contains(&lower);
+            (distance <= max_distance || contained).then_some((distance, name))
+        })
+        .collect();
+    close.sort();
+
+    close.into_iter().take(5).map(|(_, name)| name).collect()
+}
+
+/// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Largest font size in points, of the config and of the markup spans
+pub const MAX_FONT_SIZE: f32 = 1000.0;
+
+/// Scale of the font in pixels from its size in points
+pub fn font_scale(font_size: f32) -> Scale {
+    let px_font = font_size * 96.0 / 72.0;
+    Scale::uniform(px_font)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::parser::TextAlignment;
+
+    fn props(name: &str, fallback: &[&str]) -> FontProps {
+        FontProps {
+            name: name.to_string(),
+            path: None,
+            weight: FontWeight(400),
+            style: FontStyle::Normal,
+            stretch: FontStretch::Normal,
+            fallback: fallback.iter().map(|family| family.to_string()).collect(),
+            size: 12.0,
+            color: Color { r: 0, g: 0, b: 0, a: 255 },
+            intra_line: 0.0,
+            text_alignment: TextAlignment::Center,
+            markup: false,
+            linear_blending: false,
+            contrast: 0.0,
+        }
+    }
+
+    #[test]
+    fn missing_fallback_is_skipped() {
+        let chain = FontChain::load(&props("sans-serif", &["No Such Font Family", "monospace"]), FontVariant::default()).unwrap();
+        // The configured font and the fallback that exists
+        assert_eq!(chain.faces.borrow().len(), 2);
+    }
+
+    #[test]
+    fn missing_primary_font_is_an_error() {
+        assert!(matches!(Fonts::new(&props("No Such Font Family", &[])), Err(FontError::NotFound { .. })));
+    }
+}
@@ -0,0 +1,114 @@
+use rusttype::PositionedGlyph;
+use rustybuzz::ttf_parser::{GlyphId, RasterGlyphImage, RasterImageFormat};
+
+use crate::color::Color;
+use crate::colr::paint_colr;
+use crate::composite::{self, Argb, Pixel};
+use crate::font::FontFace;
+use crate::image::decode_png;
+
+/// A glyph drawn in its own colors (emoji), its pixels are premultiplied Argb8888
+/// in rows `width` long, placed with the top left corner at `x`, `y` in the coordinates of the line
+pub struct ColorGlyph {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub pixels: Vec<Argb>,
+}
+
+impl ColorGlyph {
+    pub fn from_premultiplied(x: i32, y: i32, width: u32, pixels: &[Pixel]) -> Self {
+        ColorGlyph { x, y, width, pixels: pixels.iter().map(|pixel| composite::to_argb(*pixel)).collect() }
+    }
+}
+
+/// Render `glyph` of `font` in its own colors, from the COLR paints or from the
+/// closest bitmap strike (CBDT, sbix). The COLR paints without a color of their own
+/// take `foreground`, the color of the text. `None` if the glyph is a plain outline
+pub fn color_glyph(font: &FontFace, glyph: &PositionedGlyph, foreground: Color) -> Option<ColorGlyph> {
+
+    if !font.is_color() {
+        return None;
+    }
+    let face = font.shaping_face();
+    let id = GlyphId(glyph.id().0);
+    let px_per_unit = font.px_per_unit(glyph.scale());
+    let origin = glyph.position();
+
+    if face.is_color_glyph(id) {
+        return paint_colr(face, id, px_per_unit, (origin.x, origin.y), foreground);
+    }
+
+    let pixels_per_em = px_per_unit * face.units_per_em() as f32;
+    let image = face.glyph_raster_image(id, pixels_per_em.round().clamp(1.0, u16::MAX as f32) as u16)?;
+    scale_raster_image(&image, pixels_per_em, (origin.x, origin.y))
+}
+
+/// Decode a bitmap strike image in premultiplied pixels, only the color formats are supported
+fn decode_raster_image(image: &RasterGlyphImage) -> Option<(u32, u32, Vec<Pixel>)> {
+
+    match image.format {
+        RasterImageFormat::PNG => {
+            let decoded = decode_png(image.data).ok()?;
+            let pixels = decoded.pixels
+                .iter()
+                .map(|pixel| {
+                    let [a, r, g, b] = pixel.to_be_bytes();
+                    [r, g, b, a].map(|c| c as f32 / 255.0)
+                })
+                .collect();
+            Some((decoded.width, decoded.height, pixels))
+        }
+        RasterImageFormat::BitmapPremulBgra32 => {
+            let pixels = image.data
+                .chunks_exact(4)
+                .map(|p| [p[2], p[1], p[0], p[3]].map(|c| c as f32 / 255.0))
+                .collect();
+            Some((image.width as u32, image.height as u32, pixels))
+        }
+        _ => None,
+    }
+}
+
+/// Scale a bitmap strike to `pixels_per_em`, averaging the pixels of the strike each pixel covers
+fn scale_raster_image(image: &RasterGlyphImage, pixels_per_em: f32, origin: (f32, f32)) -> Option<ColorGlyph> {
+
+    let (src_w, src_h, src) = decode_raster_image(image)?;
+    if src_w == 0 || src_h == 0 || src.len() < (src_w * src_h) as usize {
+        return None;
+    }
+
+    let factor = pixels_per_em / image.pixels_per_em as f32;
+    let width = ((src_w as f32 * factor).round() as u32).max(1);
+    let height = ((src_h as f32 * factor).round() as u32).max(1);
+
+    // The offsets of the strike are from the origin to the bottom left corner, y grows up
+    let x = (origin.0 + image.x as f32 * factor).round() as i32;
+    let y = (origin.1 - (image.y as f32 * factor + height as f32)).round() as i32;
+
+    let step_x = src_w as f32 / width as f32;
+    let step_y = src_h as f32 / height as f32;
+    let mut pixels: Vec<Pixel> = Vec::with_capacity((width * height) as usize);
+
+    for ty in 0..height {
+        let y0 = (ty as f32 * step_y) as u32;
+        let y1 = (((ty + 1) as f32 * step_y).ceil() as u32).clamp(y0 + 1, src_h);
+
+        for tx in 0..width {
+            let x0 = (tx as f32 * step_x) as u32;
+            let x1 = (((tx + 1) as f32 * step_x).ceil() as u32).clamp(x0 + 1, src_w);
+
+            let mut sum = [0.0; 4];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let p = src[(sy * src_w + sx) as usize];
+                    (0..4).for_each(|c| sum[c] += p[c]);
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as f32;
+            pixels.push(sum.map(|c| c / count));
+        }
+    }
+
+    Some(ColorGlyph::from_premultiplied(x, y, width, &pixels))
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::xdg::{xdg_dir, xdg_dirs};
+
+/// Theme every icon theme implicitly inherits from
+static FALLBACK_THEME: &str = "hicolor";
+
+/// Extensions of the icon files, in order of preference (XPM is not supported)
+static EXTENSIONS: [&str; 2] = ["png", "svg"];
+
+/// How the icons of a directory of a theme can be scaled
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SizeType {
+    Fixed,
+    Scalable,
+    Threshold,
+}
+
+/// A directory of a theme holding icons of the same size
+#[derive(Debug)]
+struct Directory {
+    path: String,
+    size: u32,
+    scale: u32,
+    min_size: u32,
+    max_size: u32,
+    threshold: u32,
+    kind: SizeType,
+}
+
+impl Directory {
+    fn matches_size(&self, size: u32) -> bool {
+        if self.scale != 1 {
+            return false;
+        }
+        match self.kind {
+            SizeType::Fixed => self.size == size,
+            SizeType::Scalable => (self.min_size..=self.max_size).contains(&size),
+            SizeType::Threshold => self.size.abs_diff(size) <= self.threshold,
+        }
+    }
+
+    /// How far the icons of this directory are from `size`, 0 if they fit it
+    fn size_distance(&self, size: u32) -> u32 {
+        let (min, max) = match self.kind {
+            SizeType::Fixed => (self.size, self.size),
+            SizeType::Scalable => (self.min_size, self.max_size),
+            SizeType::Threshold => (self.size.saturating_sub(self.threshold), self.size + self.threshold),
+        };
+        let (min, max) = (min * self.scale, max * self.scale);
+        if size < min {
+            min - size
+        } else {
+            size.saturating_sub(max)
+        }
+    }
+}
+
+/// An icon theme, as described by its index.theme
+#[derive(Debug)]
+struct Theme {
+    name: String,
+    inherits: Vec<String>,
+    directories: Vec<Directory>,
+}
+
+impl Theme {
+    /// Load the theme `name` from the first base directory having its index.theme
+    fn load(name: &str, base_dirs: &[PathBuf]) -> Option<Self> {
+
+        let index = base_dirs.iter().find_map(|dir| fs::read_to_string(dir.join(name).join("index.theme")).ok())?;
+        let sections = parse_ini(&index);
+        let theme = sections.get("Icon Theme")?;
+
+        let list = |key: &str| -> Vec<String> {
+            theme
+                .get(key)
+                .map(|value| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+                .unwrap_or_default()
+        };
+
+        let mut paths = list("Directories");
+        for path in list("ScaledDirectories") {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+
+        let directories = paths
+            .into_iter()
+            .filter_map(|path| {
+                let section = sections.get(path.as_str())?;
+                let number = |key: &str| section.get(key).and_then(|value| value.parse::<u32>().ok());
+                let size = number("Size")?;
+                let kind = match section.get("Type").map(String::as_str) {
+                    Some("Fixed") => SizeType::Fixed,
+                    Some("Scalable") => SizeType::Scalable,
+                    _ => SizeType::Threshold,
+                };
+                Some(Directory {
+                    path,
+                    size,
+                    scale: number("Scale").unwrap_or(1),
+                    min_size: number("MinSize").unwrap_or(size),
+                    max_size: number("MaxSize").unwrap_or(size),
+                    threshold: number("Threshold").unwrap_or(2),
+                    kind,
+                })
+            })
+            .collect();
+
+        Some(Theme { name: name.to_string(), inherits: list("Inherits"), directories })
+    }
+
+    /// The icon `name` in this theme only: one in a directory of the right size, otherwise the closest one
+    fn lookup(&self, name: &str, size: u32, base_dirs: &[PathBuf]) -> Option<PathBuf> {
+
+        let exact = self
+            .directories
+            .iter()
+            .filter(|directory| directory.matches_size(size))
+            .find_map(|directory| self.find_file(directory, name, base_dirs));
+        if exact.is_some() {
+            return exact;
+        }
+
+        self.directories
+            .iter()
+            .filter_map(|directory| self.find_file(directory, name, base_dirs).map(|file| (directory.size_distance(size), file)))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, file)| file)
+    }
+
+    /// The icon `name` in `directory`, in the first base directory having it
+    fn find_file(&self, directory: &Directory, name: &str, base_dirs: &[PathBuf]) -> Option<PathBuf> {
+        base_dirs
+            .iter()
+            .flat_map(|base| EXTENSIONS.iter().map(move |extension| base.join(&self.name).join(&directory.path).join(format!("{}.{}", name, extension))))
+            .find(|file| file.is_file())
+    }
+}
+
+/// Find the icon `name` as the freedesktop Icon Theme Specification describes: in `theme` and
+/// the themes it inherits from, then in hicolor, then among the icons without a theme.
+/// `size` is the side in pixels the icon is drawn at, the closest size available is picked
+pub fn find_icon(name: &str, theme: Option<&str>, size: u32) -> Option<PathBuf> {
+
+    let base_dirs = base_dirs();
+    let mut visited = Vec::new();
+
+    theme
+        .into_iter()
+        .chain([FALLBACK_THEME])
+        .find_map(|theme| find_in_theme(name, theme, size, &base_dirs, &mut visited))
+        .or_else(|| find_unthemed(name, &base_dirs))
+}
+
+/// Look up the icon in `theme` and, if missing, in its parents. Themes already searched are skipped
+fn find_in_theme(name: &str, theme: &str, size: u32, base_dirs: &[PathBuf], visited: &mut Vec<String>) -> Option<PathBuf> {
+
+    if visited.iter().any(|searched| searched == theme) {
+        return None;
+    }
+    visited.push(theme.to_string());
+
+    let theme = Theme::load(theme, base_dirs)?;
+    theme
+        .lookup(name, size, base_dirs)
+        .or_else(|| theme.inherits.iter().find_map(|parent| find_in_theme(name, parent, size, base_dirs, visited)))
+}
+
+/// Icons directly in the base directories, outside of any theme
+fn find_unthemed(name: &str, base_dirs: &[PathBuf]) -> Option<PathBuf> {
+    base_dirs
+        .iter()
+        .flat_map(|dir| EXTENSIONS.iter().map(move |extension| dir.join(format!("{}.{}", name, extension))))
+        .find(|file| file.is_file())
+}
+
+/// Directories where the themes are looked up, in order of priority:
+/// $HOME/.icons, $XDG_DATA_HOME/icons, $XDG_DATA_DIRS/icons and /usr/share/pixmaps
+fn base_dirs() -> Vec<PathBuf> {
+
+    let mut dirs: Vec<PathBuf> = Vec::new();
+
+    dirs.extend(dirs::home_dir().map(|home| home.join(".icons")));
+    dirs.extend(xdg_dir("XDG_DATA_HOME", ".local/share").map(|dir| dir.join("icons")));
+    dirs.extend(xdg_dirs("XDG_DATA_DIRS", "/usr/local/share:/usr/share").into_iter().map(|dir| dir.join("icons")));
+
+    dirs.push(PathBuf::from("/usr/share/pixmaps"));
+    dirs
+}
+
+/// The icon theme set in the GTK settings of the user, or in the system wide ones
+pub fn gtk_icon_theme() -> Option<String> {
+
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    dirs.extend(xdg_dir("XDG_CONFIG_HOME", ".config"));
+    dirs.extend(["/etc/xdg", "/etc"].map(PathBuf::from));
+
+    dirs.iter()
+        .filter_map(|dir| fs::read_to_string(dir.join("gtk-3.0/settings.ini")).ok())
+        .find_map(|settings| parse_ini(&settings).get("Settings")?.get("gtk-icon-theme-name").cloned())
+        .filter(|theme| !theme.is_empty())
+}
+
+/// Sections of an ini file, with the keys and values of each one. Comments start with '#' or ';'
+fn parse_ini(content: &str) -> HashMap<&str, HashMap<&str, String>> {
+
+    let mut sections: HashMap<&str, HashMap<&str, String>> = HashMap::new();
+    let mut current = None;
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            current = Some(section);
+            sections.entry(section).or_default();
+        } else if let (Some(section), Some((key, value))) = (current, line.split_once('=')) {
+            let value = value.trim().trim_matches('"').to_string();
+            sections.entry(section).or_default().entry(key.trim()).or_insert(value);
+        }
+    }
+
+    sections
+}
@@ -0,0 +1,222 @@
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use resvg::{tiny_skia, usvg};
+use thiserror::Error;
+
+use crate::color::Color;
+use crate::composite::{self, Argb};
+
+/// A decoded image, its pixels are premultiplied Argb8888 in rows `width` long
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Argb>,
+}
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("cannot read the image {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("cannot decode the image {}: {message}", path.display())]
+    Decode { path: PathBuf, message: String },
+
+    #[error("{} is not a PNG, JPEG or SVG image", path.display())]
+    Unsupported { path: PathBuf },
+}
+
+impl Image {
+    /// Load a PNG, JPEG or SVG file, the format is recognized from its content.
+    /// An SVG is rendered at the size it declares
+    pub fn load(path: &Path) -> Result<Self, ImageError> {
+        Image::decode(path, None)
+    }
+
+    /// Load an image scaled to fit in a square of side `size`, keeping its aspect ratio.
+    /// An SVG is rendered directly at that size, to keep it sharp
+    pub fn load_fit(path: &Path, size: u32) -> Result<Self, ImageError> {
+
+        let image = Image::decode(path, Some(size))?;
+        let factor = size as f32 / image.width.max(image.height).max(1) as f32;
+        let width = ((image.width as f32 * factor).round() as u32).max(1);
+        let height = ((image.height as f32 * factor).round() as u32).max(1);
+
+        Ok(image.resize(width, height))
+    }
+
+    fn decode(path: &Path, fit: Option<u32>) -> Result<Self, ImageError> {
+
+        let data = fs::read(path).map_err(|source| ImageError::Io { path: path.to_path_buf(), source })?;
+        let decode_error = |message: String| ImageError::Decode { path: path.to_path_buf(), message };
+
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            decode_png(&data).map_err(decode_error)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            decode_jpeg(&data).map_err(decode_error)
+        } else if is_svg(path, &data) {
+            decode_svg(&data, fit).map_err(decode_error)
+        } else {
+            Err(ImageError::Unsupported { path: path.to_path_buf() })
+        }
+    }
+
+    /// Pixel at `x`, `y`, the closest one on the edge outside of the image
+    fn pixel(&self, x: i64, y: i64) -> Argb {
+        let x = x.clamp(0, self.width as i64 - 1) as u32;
+        let y = y.clamp(0, self.height as i64 - 1) as u32;
+        self.pixels[(x + y * self.width) as usize]
+    }
+
+    /// The image scaled to `width` x `height`: each pixel is the average of the ones it covers
+    /// when shrinking, and interpolated between the four closest ones when enlarging
+    pub fn resize(&self, width: u32, height: u32) -> Image {
+
+        if (width, height) == (self.width, self.height) || self.pixels.is_empty() {
+            return self.clone();
+        }
+        if width == 0 || height == 0 {
+            return Image { width, height, pixels: Vec::new() };
+        }
+
+        let factor = (self.width as f32 / width as f32, self.height as f32 / height as f32);
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let area = (
+                    x as f32 * factor.0..(x + 1) as f32 * factor.0,
+                    y as f32 * factor.1..(y + 1) as f32 * factor.1,
+                );
+                let channels = if factor.0 > 1.0 || factor.1 > 1.0 {
+                    self.average(area)
+                } else {
+                    self.interpolate(((area.0.start + area.0.end) / 2.0, (area.1.start + area.1.end) / 2.0))
+                };
+                pixels.push(u32::from_be_bytes(channels.map(|c| c.round().clamp(0.0, 255.0) as u8)));
+            }
+        }
+
+        Image { width, height, pixels }
+    }
+
+    /// Average of the pixels overlapping `area` (x, y), in premultiplied channels
+    fn average(&self, area: (Range<f32>, Range<f32>)) -> [f32; 4] {
+
+        let (mut sum, mut count) = ([0.0; 4], 0.0);
+        for y in (area.1.start as i64)..(area.1.end.ceil() as i64).max(area.1.start as i64 + 1) {
+            for x in (area.0.start as i64)..(area.0.end.ceil() as i64).max(area.0.start as i64 + 1) {
+                let channels = self.pixel(x, y).to_be_bytes();
+                (0..4).for_each(|i| sum[i] += channels[i] as f32);
+                count += 1.0;
+            }
+        }
+        sum.map(|c| c / count)
+    }
+
+    /// Bilinear interpolation at `point`, pixel centers are at half coordinates
+    fn interpolate(&self, point: (f32, f32)) -> [f32; 4] {
+
+        let (x, y) = (point.0 - 0.5, point.1 - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let corners = [
+            (self.pixel(x0, y0), (1.0 - tx) * (1.0 - ty)),
+            (self.pixel(x0 + 1, y0), tx * (1.0 - ty)),
+            (self.pixel(x0, y0 + 1), (1.0 - tx) * ty),
+            (self.pixel(x0 + 1, y0 + 1), tx * ty),
+        ];
+        let mut channels = [0.0; 4];
+        for (pixel, weight) in corners {
+            let bytes = pixel.to_be_bytes();
+            (0..4).for_each(|i| channels[i] += bytes[i] as f32 * weight);
+        }
+        channels
+    }
+}
+
+/// SVG files are text (or compressed text for .svgz), recognized from the extension or the markup
+fn is_svg(path: &Path, data: &[u8]) -> bool {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+    matches!(extension.as_deref(), Some("svg" | "svgz")) || data.trim_ascii_start().starts_with(b"<")
+}
+
+fn from_rgba([r, g, b, a]: [u8; 4]) -> Argb {
+    composite::premultiply(Color { r, g, b, a })
+}
+
+/// Decode a PNG image of any color type and bit depth
+pub fn decode_png(data: &[u8]) -> Result<Image, String> {
+
+    let mut decoder = png::Decoder::new(Cursor::new(data));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size().ok_or("image too big")?];
+    let info = reader.next_frame(&mut buffer).map_err(|err| err.to_string())?;
+
+    let to_rgba: fn(&[u8]) -> [u8; 4] = match info.color_type {
+        png::ColorType::Rgba => |p| [p[0], p[1], p[2], p[3]],
+        png::ColorType::Rgb => |p| [p[0], p[1], p[2], 255],
+        png::ColorType::GrayscaleAlpha => |p| [p[0], p[0], p[0], p[1]],
+        png::ColorType::Grayscale => |p| [p[0], p[0], p[0], 255],
+        png::ColorType::Indexed => return Err("indexed colors left after the expansion".to_string()),
+    };
+
+    let pixels = buffer[..info.buffer_size()]
+        .chunks_exact(info.color_type.samples())
+        .map(|p| from_rgba(to_rgba(p)))
+        .collect();
+
+    Ok(Image { width: info.width, height: info.height, pixels })
+}
+
+fn decode_jpeg(data: &[u8]) -> Result<Image, String> {
+
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+    let buffer = decoder.decode().map_err(|err| err.to_string())?;
+    let info = decoder.info().ok_or("missing image info")?;
+
+    let to_rgba: fn(&[u8]) -> [u8; 4] = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => |p| [p[0], p[1], p[2], 255],
+        jpeg_decoder::PixelFormat::L8 => |p| [p[0], p[0], p[0], 255],
+        // Big endian 16 bit luminance, the high byte is enough
+        jpeg_decoder::PixelFormat::L16 => |p| [p[0], p[0], p[0], 255],
+        jpeg_decoder::PixelFormat::CMYK32 => |p| {
+            let white = |c: u8| ((255 - c as u32) * (255 - p[3] as u32) / 255) as u8;
+            [white(p[0]), white(p[1]), white(p[2]), 255]
+        },
+    };
+    let samples = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => 1,
+        jpeg_decoder::PixelFormat::L16 => 2,
+        jpeg_decoder::PixelFormat::RGB24 => 3,
+        jpeg_decoder::PixelFormat::CMYK32 => 4,
+    };
+
+    let pixels = buffer.chunks_exact(samples).map(|p| from_rgba(to_rgba(p))).collect();
+
+    Ok(Image { width: info.width as u32, height: info.height as u32, pixels })
+}
+
+/// Render an SVG at the size it declares, or scaled to fit in a square of side `fit`
+fn decode_svg(data: &[u8], fit: Option<u32>) -> Result<Image, String> {
+
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default()).map_err(|err| err.to_string())?;
+    let size = tree.size();
+    let factor = fit.map_or(1.0, |fit| fit as f32 / size.width().max(size.height()));
+    let width = ((size.width() * factor).round() as u32).max(1);
+    let height = ((size.height() * factor).round() as u32).max(1);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or("image too big")?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(factor, factor), &mut pixmap.as_mut());
+
+    // tiny-skia pixels are premultiplied RGBA too
+    let pixels = pixmap.data().chunks_exact(4).map(|p| u32::from_be_bytes([p[3], p[0], p[1], p[2]])).collect();
+
+    Ok(Image { width, height, pixels })
+}
@@ -1,4 +1,18 @@
+mod background;
+mod canvas;
+mod cli;
+mod color;
+mod colr;
+mod composite;
+mod font;
+mod glyph;
+mod icon;
+mod image;
+mod markup;
 mod parser;
+mod render;
+mod text;
+mod xdg;
 
 use smithay_client_toolkit::{
     default_environment,
@@ -9,6 +23,7 @@ use smithay_client_toolkit::{
         calloop::{timer::Timer, EventLoop, LoopSignal},
         client::protocol::{wl_output, wl_shm, wl_surface},
         client::{Attached, Main},
+        protocols::unstable::xdg_output::v1::client::{zxdg_output_manager_v1, zxdg_output_v1},
         protocols::wlr::unstable::layer_shell::v1::client::{
             zwlr_layer_shell_v1, zwlr_layer_surface_v1,
         },
@@ -18,29 +33,26 @@ use smithay_client_toolkit::{
 };
 
 use std::cell::{RefCell, Cell};
+use std::process;
 use std::rc::Rc;
-use std::env;
 
-use font_loader::system_fonts;
-use rusttype::{point, Font, Scale, PositionedGlyph};
+use clap::Parser;
 
-use parser::Config;
+use markup::StyledText;
+use parser::{AxisAlign, Config};
+use render::Resources;
 
 default_environment!(Env,
     fields = [
         layer_shell: SimpleGlobal<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
+        xdg_output_manager: SimpleGlobal<zxdg_output_manager_v1::ZxdgOutputManagerV1>,
     ],
     singles = [
-        zwlr_layer_shell_v1::ZwlrLayerShellV1 => layer_shell
+        zwlr_layer_shell_v1::ZwlrLayerShellV1 => layer_shell,
+        zxdg_output_manager_v1::ZxdgOutputManagerV1 => xdg_output_manager
     ],
 );
 
-struct Color {
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
 #[derive(PartialEq, Copy, Clone)]
 enum RenderEvent {
     Configure { width: u32, height: u32 },
@@ -53,56 +65,37 @@ struct Surface {
     next_render_event: Rc<Cell<Option<RenderEvent>>>,
     pool: AutoMemPool,
     dimensions: (u32, u32),
-    vec_canvas: Vec<u32>
+    vec_canvas: Vec<u32>,
+    /// Size of `vec_canvas`, the one requested for the layer surface
+    canvas_dimensions: (u32, u32),
+    config: Rc<Config>,
+    resources: Rc<Resources>,
+    text: Vec<StyledText>,
+    /// Logical size of the output the box is laid out for
+    display_dimensions: (u32, u32),
+    /// Logical size of the output sent through xdg-output, not yet laid out for
+    next_display_dimensions: Rc<Cell<Option<(u32, u32)>>>,
+    xdg_output: Option<Main<zxdg_output_v1::ZxdgOutputV1>>,
 }
 
 impl Surface {
     fn new(
-        //output: &wl_output::WlOutput,
         surface: wl_surface::WlSurface,
         layer_shell: &Attached<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
         pool: AutoMemPool,
         display_dimensions: (u32, u32),
         config: Rc<Config>,
-        text: Vec<String>,
+        resources: Rc<Resources>,
+        text: Vec<StyledText>,
     ) -> Self {
 
         let layer_surface = layer_shell.get_layer_surface(
             &surface,
-            //Some(output), // maybe in the future if we are able to specify a monitor
             None, // only recently used monitor
             zwlr_layer_shell_v1::Layer::Overlay,
             "gwstuff".to_owned(),
         );
 
-        // Calc window dimensions and get glyphs alread positioned
-        let ((win_w, win_h), vec_canvas) = get_dimensions_and_canvas(Rc::clone(&config), &text);
-        
-        layer_surface.set_size(win_w, win_h);
-
-        let anchor = zwlr_layer_surface_v1::Anchor::from_raw(config.window.win_position.unwrap().0.to_raw() | config.window.win_position.unwrap().1.to_raw()).unwrap();
-
-        if !anchor.contains(zwlr_layer_surface_v1::Anchor::from_raw(15).unwrap()) {
-
-            layer_surface
-                .set_anchor(anchor);
-
-            let calc_px_margin = |val: u8, tot: u32| ((val as u32 * tot) / 100) as i32;
-
-            let horizontal_margin_px = calc_px_margin(config.margins.horizontal_percentage, display_dimensions.0);
-            let vertical_margin_px = calc_px_margin(config.margins.vertical_percentage, display_dimensions.1);
-
-            let get_proper_margin = |a: zwlr_layer_surface_v1::Anchor, val: i32| if anchor.contains(a) { val } else { 0 };
-
-            layer_surface
-                .set_margin(
-                    get_proper_margin(zwlr_layer_surface_v1::Anchor::Top, vertical_margin_px),
-                    get_proper_margin(zwlr_layer_surface_v1::Anchor::Right, vertical_margin_px),
-                    get_proper_margin(zwlr_layer_surface_v1::Anchor::Bottom, vertical_margin_px),
-                    get_proper_margin(zwlr_layer_surface_v1::Anchor::Left, horizontal_margin_px),
-                );
-        }
-
         let next_render_event = Rc::new(Cell::new(None::<RenderEvent>));
         let next_render_event_handle = Rc::clone(&next_render_event);
         layer_surface.quick_assign(move |layer_surface, event, _| {
@@ -120,31 +113,96 @@ impl Surface {
             }
         });
 
+        let mut surface = Self {
+            surface,
+            layer_surface,
+            next_render_event,
+            pool,
+            // Nothing is drawn until the first configure event
+            dimensions: (0, 0),
+            vec_canvas: Vec::new(),
+            canvas_dimensions: (0, 0),
+            config,
+            resources,
+            text,
+            display_dimensions,
+            next_display_dimensions: Rc::new(Cell::new(None)),
+            xdg_output: None,
+        };
+        surface.layout();
+
         // Commit so that the server will send a configure event
-        surface.commit();
-
-        // TODO how this work? Why need (0, 0) in dimensions?
-        Self { 
-            surface, 
-            layer_surface, 
-            next_render_event, 
-            pool, 
-            //dimensions: (win_w, win_h), 
-            dimensions: (0, 0), 
-            vec_canvas
-        }
+        surface.surface.commit();
+
+        surface
+    }
+
+    /// Lay the box out again for the logical size of `output` whenever xdg-output sends it.
+    /// The size computed from the scale factor of the output is wrong with fractional scaling
+    fn follow_xdg_output(&mut self, manager: &Attached<zxdg_output_manager_v1::ZxdgOutputManagerV1>, output: &wl_output::WlOutput) {
+
+        let xdg_output = manager.get_xdg_output(output);
+        let next_display_dimensions_handle = Rc::clone(&self.next_display_dimensions);
+        xdg_output.quick_assign(move |_, event, _| {
+            if let zxdg_output_v1::Event::LogicalSize { width, height } = event {
+                if width > 0 && height > 0 {
+                    next_display_dimensions_handle.set(Some((width as u32, height as u32)));
+                }
+            }
+        });
+        self.xdg_output = Some(xdg_output);
+    }
+
+    /// Render the box for the current output size and place the layer surface,
+    /// the new state is applied on the next commit
+    fn layout(&mut self) {
+
+        // Calc window dimensions and get glyphs alread positioned
+        let (canvas_dimensions, vec_canvas) =
+            render::get_dimensions_and_canvas(&self.config, &self.resources, &self.text, self.display_dimensions);
+
+        self.layer_surface.set_size(canvas_dimensions.0, canvas_dimensions.1);
+
+        let (anchor, (top, right, bottom, left)) = calc_placement(&self.config, self.display_dimensions, canvas_dimensions);
+
+        self.layer_surface.set_anchor(anchor);
+        self.layer_surface.set_margin(top, right, bottom, left);
+
+        self.canvas_dimensions = canvas_dimensions;
+        self.vec_canvas = vec_canvas;
     }
 
     /// Handles any events that have occurred since the last call, redrawing if needed.
     /// Returns true if the surface should be dropped.
     fn handle_events(&mut self) -> bool {
+
+        if let Some(display_dimensions) = self.next_display_dimensions.take() {
+            if display_dimensions != self.display_dimensions {
+                self.display_dimensions = display_dimensions;
+                let requested = self.canvas_dimensions;
+                self.layout();
+                if self.canvas_dimensions != requested {
+                    // A configure received until now answers the old size request,
+                    // the one for the new size follows this commit
+                    if let Some(RenderEvent::Configure { .. }) = self.next_render_event.get() {
+                        self.next_render_event.set(None);
+                    }
+                    self.surface.commit();
+                } else if self.dimensions != (0, 0) && self.next_render_event.get().is_none() {
+                    // The compositor won't configure the surface again for the same size
+                    self.draw();
+                }
+            }
+        }
+
         match self.next_render_event.take() {
             Some(RenderEvent::Closed) => true,
             Some(RenderEvent::Configure { width, height }) => {
-                if self.dimensions != (width, height) {
-                    self.dimensions = (width, height);
-                    self.draw();
-                }
+                // A size of 0 is left to the surface. The compositor may pick another
+                // size than the requested one (e.g. clamped to the output), the box is clipped to it
+                let size = |configured: u32, requested: u32| if configured == 0 { requested } else { configured };
+                self.dimensions = (size(width, self.canvas_dimensions.0), size(height, self.canvas_dimensions.1));
+                self.draw();
                 false
             }
             None => false,
@@ -163,272 +221,116 @@ impl Surface {
         let (canvas, buffer) =
             self.pool.buffer(width, height, stride, wl_shm::Format::Argb8888).unwrap();
 
-
-        //draw_line(canvas, (width as u32, height as u32), (50, 10), (100, 200), 2, (155, 0, 0));
-
-        for (index, color) in self.vec_canvas.iter().enumerate() {
-
-
-            let pixel = color.to_ne_bytes();
-            let pixel_canvas = canvas.chunks_exact_mut(4).nth(index).unwrap();
-
-            pixel_canvas[0] = pixel[0];
-            pixel_canvas[1] = pixel[1];
-            pixel_canvas[2] = pixel[2];
-            pixel_canvas[3] = pixel[3];
+        // The rows of the box are cut or padded with transparent pixels to the configured width
+        let mut rows = self.vec_canvas.chunks_exact(self.canvas_dimensions.0.max(1) as usize);
+        for dst_row in canvas.chunks_exact_mut(stride as usize) {
+            let mut dst = dst_row.chunks_exact_mut(4);
+            for (dst, src) in dst.by_ref().zip(rows.next().unwrap_or(&[])) {
+                dst.copy_from_slice(&src.to_ne_bytes());
+            }
+            dst.for_each(|dst| dst.fill(0));
         }
 
-        // here I should place the canvas_vec into the canvas
-
-
         // Attach the buffer to the surface and mark the entire surface as damaged
         self.surface.attach(Some(&buffer), 0, 0);
-        self.surface.damage_buffer(0, 0, width as i32, height as i32);
+        self.surface.damage_buffer(0, 0, width, height);
 
         // Finally, commit the surface
         self.surface.commit();
     }
 }
 
-fn get_canvas(config: Rc<Config>, text_and_width: &Vec<(Vec<PositionedGlyph>, u32)>, dimensions: (u32, u32)) -> Vec<u32> {
-
-    let mut canvas: Vec<u32> = Vec::new();
-    set_backgorund(Rc::clone(&config), &mut canvas, dimensions);
-
-    //let pixel = config.font.color.to_ne_bytes();
-
-    let dim_y = text_and_width[0].0[0].scale().y as u32;
-    let mut init_x: u32;
-    let mut init_y: u32 = config.window.vertical_padding;
-
-    for (glyphs, width_line) in text_and_width.iter() {
-
-        match config.font.text_alignment {
-            parser::TextAlignment::Left => {
-                init_x = config.window.horizontal_padding;
-            },
-            parser::TextAlignment::Right => {
-                init_x = dimensions.0 - config.window.horizontal_padding - width_line;
-            },
-            parser::TextAlignment::Center => {
-                init_x = (dimensions.0 / 2) - (width_line / 2);
-            }
-        }
-
-        for g in glyphs.iter() {
-            if let Some(bb) = g.pixel_bounding_box() {
-                g.draw(|x, y, v| {
-
-                    // v should be in the range 0.0 to 1.0
-                    let x = x as i32 + bb.min.x;
-                    let y = y as i32 + bb.min.y;
-                    // There's still a possibility that the glyph clips the boundaries of the bitmap
-                    if x >= 0 && x < *width_line as i32 && y >= 0 && y < dim_y as i32 /*&& v >= 0.1*/ {
-                        let x = x as u32;
-                        let y = y as u32;
-                        
-                        //let pixel = add_opacity(config.font.color, 0);
-                        //println!("{}", (v * 255.0).floor() as u8);
-                        //let pixel = add_opacity(config.font.color, (v * 255.0).floor() as u8);
-                        //
-                        let mul_color = |color: u32, coverage: f32| -> u32 {
-
-                            let mut color_bytes = color.to_ne_bytes();
-                            for i in 0..4 {
-                                color_bytes[i] = (color_bytes[i] as f32 * coverage).floor() as u8;
-                            }
-                            ((color_bytes[3] as u32) << 24) + ((color_bytes[2] as u32) << 16) + ((color_bytes[1] as u32) << 8) + (color_bytes[0] as u32)
-                        };
-
-                        // config color: rgb 0000rrrrggggbbbbbbbbb
-
-                        let pixel_font = mul_color(add_opacity(config.font.color, 255), v);
-                        let pixel_bg = mul_color(add_opacity(config.window.background_color, percentage_to_u8(config.window.background_opacity)), 1.0 - v);
-                        let pixel = (pixel_font + pixel_bg) as u32;
-
-                        canvas[(init_x + x + ((init_y + y) * dimensions.0)) as usize] = pixel;
-                        
-                        /*
-                        let pixel_canvas = canvas.chunks_exact_mut(4).nth((init_x + x + ((init_y + y) * dimensions.0)) as usize).unwrap();
-
-                        pixel_canvas[0] = pixel[0];
-                        pixel_canvas[1] = pixel[1];
-                        pixel_canvas[2] = pixel[2];
-                        pixel_canvas[3] = pixel[3];
-                        */
-                    }
-                })
-            }
-        }
-        init_y += (config.font.intra_line as u32) + dim_y;
-    }
-    canvas
-}
-
-fn get_dimensions_and_canvas(config: Rc<Config>, text: &Vec<String>) -> ((u32, u32), Vec<u32>) {
-
-    let (font, scale) = load_font_and_scale(config.font.name.clone(), config.font.size);
-
-    let mut text_glyphs_and_width: Vec<(Vec<PositionedGlyph>, u32)> = Vec::new();
-
-    let v_metrics = font.v_metrics(scale);
-
-    //let text_glyphs_and_width: Rc<RefCell<Vec<(Vec<PositionedGlyph>, u32)>>> = Rc::new(RefCell::new(Vec::new()));
-
-    let mut win_h: f32 = 0.0;
-    let mut win_w: u32 = 0;
-
-    for (index, line) in text.iter().enumerate() {
-
-        let layout_iter: rusttype::LayoutIter = font.layout(line, scale, point(0.0, v_metrics.ascent));
-        let glyphs: Vec<PositionedGlyph> = layout_iter.collect();
-
-        // Find the most visually pleasing width to display
-        let width_line = glyphs
-            .iter()
-            .rev()
-            .map(|g| g.position().x as f32 + g.unpositioned().h_metrics().advance_width)
-            .next()
-            .unwrap_or(0.0)
-            .ceil() as usize;
-
-        text_glyphs_and_width.push((glyphs, width_line as u32));
-
-        if width_line as u32 > win_w {
-            win_w = width_line as u32;
-        }
-
-        win_h += scale.y;
-
-        if index != 0 && index != text.len() {
-            win_h += config.font.intra_line;
+/// Anchor and margins along one axis of the output, `edges` and `margins` are the
+/// (start, end) ones of the axis, e.g. (Left, Right) for the horizontal one.
+/// `shadow` is the space taken by the shadow (start, end) around the box of size `win_size`,
+/// the margins place the box and not the whole surface
+fn calc_axis_placement(
+    align: AxisAlign,
+    edges: (zwlr_layer_surface_v1::Anchor, zwlr_layer_surface_v1::Anchor),
+    margins: (i32, i32),
+    offset: i32,
+    display_size: u32,
+    win_size: u32,
+    shadow: (i32, i32),
+) -> (zwlr_layer_surface_v1::Anchor, (i32, i32)) {
+    match align {
+        AxisAlign::Start => (edges.0, (margins.0 + offset - shadow.0, 0)),
+        AxisAlign::End => (edges.1, (0, margins.1 + offset - shadow.1)),
+        // Without anchors on the axis the compositor centers the surface on the whole output
+        AxisAlign::Center if offset == 0 && margins.0 == margins.1 && shadow.0 == shadow.1 => (zwlr_layer_surface_v1::Anchor::empty(), (0, 0)),
+        // Otherwise center it in the space left by the margins, using the start edge as reference
+        AxisAlign::Center => {
+            let free_space = display_size as i32 - margins.0 - margins.1 - win_size as i32;
+            (edges.0, (margins.0 + free_space / 2 + offset - shadow.0, 0))
         }
     }
-
-    win_w += 2 * config.window.horizontal_padding;
-    win_h += (2 * config.window.vertical_padding) as f32;
-
-    //((win_w, win_h.ceil() as u32), Rc::clone(&text_glyphs_and_width))
-    ((win_w, win_h.ceil() as u32), get_canvas(Rc::clone(&config), &text_glyphs_and_width, (win_w, win_h.ceil() as u32))) 
 }
 
-// FONT LOAD + SCALE DIMENSION -> TODO properly
-fn load_font_and_scale(font_name: String, font_size: f32) -> (Font<'static>, Scale) {
-    
-    // LOAD FONT
-    let property = system_fonts::FontPropertyBuilder::new().family(&font_name[..]).build();
-    let (font_data, _) = system_fonts::get(&property).unwrap();
-    
-    // RUSTTYPE
-    let font = Font::try_from_vec(font_data).unwrap_or_else(|| {
-        panic!( "error constructing a Font from data at");
-    });
-    
-    let px_font = font_size * 96.0 / 72.0;
-                            
-    let scale = Scale::uniform(px_font);
-
-    (font, scale)
+/// Anchor and margins (top, right, bottom, left) that place the layer surface
+/// of dimensions `surface_dimensions` where specified in the config.
+/// `display_dimensions` is the logical size of the output, the one of the surface coordinates
+fn calc_placement(config: &Config, display_dimensions: (u32, u32), surface_dimensions: (u32, u32)) -> (zwlr_layer_surface_v1::Anchor, (i32, i32, i32, i32)) {
+
+    let position = config.window.position;
+
+    let margins = &config.margins;
+
+    // The box is placed where specified, with its shadow around it
+    let (shadow_top, shadow_right, shadow_bottom, shadow_left) = render::shadow_extents(&config.window);
+    let win_dimensions = (
+        surface_dimensions.0 - shadow_left - shadow_right,
+        surface_dimensions.1 - shadow_top - shadow_bottom,
+    );
+
+    let (h_anchor, (left, right)) = calc_axis_placement(
+        position.anchor.horizontal(),
+        (zwlr_layer_surface_v1::Anchor::Left, zwlr_layer_surface_v1::Anchor::Right),
+        (margins.left.to_px(display_dimensions.0), margins.right.to_px(display_dimensions.0)),
+        position.x.to_px(display_dimensions.0),
+        display_dimensions.0,
+        win_dimensions.0,
+        (shadow_left as i32, shadow_right as i32),
+    );
+    let (v_anchor, (top, bottom)) = calc_axis_placement(
+        position.anchor.vertical(),
+        (zwlr_layer_surface_v1::Anchor::Top, zwlr_layer_surface_v1::Anchor::Bottom),
+        (margins.top.to_px(display_dimensions.1), margins.bottom.to_px(display_dimensions.1)),
+        position.y.to_px(display_dimensions.1),
+        display_dimensions.1,
+        win_dimensions.1,
+        (shadow_top as i32, shadow_bottom as i32),
+    );
+
+    (h_anchor | v_anchor, (top, right, bottom, left))
 }
 
-fn set_backgorund (config: Rc<Config>, canvas_vec: &mut Vec<u32>, dimensions: (u32, u32)) {
+/// Size of the output in surface coordinates: the current mode rotated by the output transform
+/// and divided by the scale factor, used until xdg-output sends the exact one
+fn logical_output_size(info: &OutputInfo) -> (u32, u32) {
 
-    let opacity = percentage_to_u8(config.window.background_opacity);
-    let pixel = add_opacity(config.window.background_color, opacity);
-    for _ in 0..dimensions.1 {
-        for _ in 0..dimensions.0 {
-            canvas_vec.push(pixel);
+    let mut dimensions: (u32, u32) = (1, 1);
+    for &mode in info.modes.iter() {
+        if mode.is_current {
+            dimensions = (mode.dimensions.0 as u32, mode.dimensions.1 as u32);
         }
     }
 
-}
-
-fn to_pixel(r: u8, g: u8, b: u8, t: u8) -> [u8; 4] {
-    (((t as u32) << 24) + ((r as u32) << 16) + ((g as u32) << 8) + (b as u32)).to_ne_bytes()
-}
-
-fn add_opacity(color: u32, transparency: u8) -> u32 {
-    ((transparency as u32) << 24 ) + color
-}
-
-fn percentage_to_u8(t: u32) -> u8 {
-    ((t * 255) / 100) as u8
-}
-
-fn length ((x, y): (f32, f32)) -> f32 {
-    f32::sqrt(x.powf(2.0) + y.powf(2.0))
-}
-
-fn normalize ((x, y): (f32, f32)) -> (f32, f32) {
-    let ln = length((x, y));
-    ((x / ln), (y / ln))
-}
-
-/*
-fn draw_line(canvas : &mut [u8], (buf_x, buf_y): (u32, u32), (x_init, y_init): (u32, u32),(x_end, y_end): (u32, u32), thikness: u32, (r, g, b): (u32, u32, u32)) {
-
-    //println!("dim x: {}", buf_x);
-    //println!("dim y: {}", buf_y);
-
-    let pixel = to_pixel(r, g, b, 1);
-    let half_thik = thikness as f32 / 2.0;
-
-    // Vector between the start and end of the line
-    //let vec1: (f32, f32) = (x_init as f32 - x_end as f32, y_init as f32 - y_end as f32);
-    let vec1: (f32, f32) = (x_end as f32 - x_init as f32, y_end as f32 - y_init as f32);
-    let len_vec1 = length(vec1);
-    //println!("length of the retta: {}", len_vec1);
-
-    for (i, dst_pixel) in canvas.chunks_exact_mut(4).enumerate() {
-
-        let x = (i as u32 % buf_x) as f32;
-        let y = (i as u32 / buf_y) as f32;
-
-        //println!("i: {}", i);
-        //println!("x: {}", x);
-        //println!("y: {}", y);
-
-        // vector be the start and the i-esimo? point
-        //let vec2: (f32, f32) = (x_init as f32 - x as f32, y_init as f32 - y as f32);
-        let vec2: (f32, f32) = (x - x_init as f32, y - y_init  as f32);
-
-        let len_proj = (vec1.0 * vec2.0 + vec1.1 * vec2.1) / len_vec1;
-
-        if len_proj > len_vec1 {
-            continue;
-        }
-
-        let normalize_vec1 = normalize(vec1);
-        let vec_proj = (normalize_vec1.0 * len_proj, normalize_vec1.1 * len_proj);
-
-        let point_proj = (x_init as f32 + vec_proj.0, y_init as f32 + vec_proj.1);
-
-        //let anti_len_proj = length((x_end as f32 - vec_proj.0 , y_end as f32 - vec_proj.1));
-        let anti_len_proj = length((point_proj.0 - x_end as f32 , point_proj.1 - y_end as f32 ));
-
-        if anti_len_proj > len_vec1 {
-            continue;
-        }
-
-        //let len_vec2 =  length(vec2);
-
-        //let ln = f32::sin(f32::acos(len_proj / len_vec2)) * len_vec2;
-        //let ln = f32::sqrt(len_vec2.powf(2.0) - len_proj.powf(2.0));
-        let point_proj = (x_init as f32 + vec_proj.0, y_init as f32 + vec_proj.1);
-        let ln = length((point_proj.0 - x, point_proj.1 - y));
-
-        if ln <= half_thik {
-            dst_pixel[0] = pixel[0];
-            dst_pixel[1] = pixel[1];
-            dst_pixel[2] = pixel[2];
-            dst_pixel[3] = pixel[3];
-        }
+    if matches!(
+        info.transform,
+        wl_output::Transform::_90 | wl_output::Transform::_270 | wl_output::Transform::Flipped90 | wl_output::Transform::Flipped270
+    ) {
+        dimensions = (dimensions.1, dimensions.0);
     }
+
+    let scale = info.scale_factor.max(1) as u32;
+    (dimensions.0 / scale, dimensions.1 / scale)
 }
-*/
 
 impl Drop for Surface {
     fn drop(&mut self) {
+        if let Some(xdg_output) = &self.xdg_output {
+            xdg_output.destroy();
+        }
         self.layer_surface.destroy();
         self.surface.destroy();
     }
@@ -436,45 +338,62 @@ impl Drop for Surface {
 
 fn main() {
 
-    // Take from line argument the text and render the glyph + screen size
-    let mut args: Vec<String> = env::args().collect();
-    args.remove(0); // remove the name of the file
-
-    if args.len() == 0 {
-        println!("No text specified");
+    // Take from line argument the options and the text to render
+    let args = cli::Args::parse();
+
+    if let Some(cli::Command::CheckConfig { config }) = args.command {
+        // The fonts and the images of a valid config must exist
+        let checked = parser::init_toml_config(config.clone())
+            .map_err(|err| err.to_string())
+            .and_then(|config| Resources::load(&config).map(|_| ()).map_err(|err| err.to_string()));
+        match checked {
+            Ok(()) => println!("Config '{}' is valid", config.as_deref().unwrap_or("default")),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        }
         return;
     }
 
-    let config_name: Option<String> = match args[0][..2] == "--".to_owned() {
-        true => {
-            let name = args[0][2..].to_string();
-            args.remove(0);
-            Some(name)
-        },
-        false => None,
-    };
-
-    let gwstuff_config: Rc<Config> = Rc::new(parser::init_toml_config(config_name));
+    let mut config = parser::init_toml_config(args.config.clone()).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    });
+    args.apply_to(&mut config);
+
+    let resources = Rc::new(Resources::load(&config).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }));
+    // Parsed once, a line with malformed markup is shown as plain text
+    let text: Vec<StyledText> = args.text
+        .iter()
+        .map(|line| {
+            text::style_line(&config, line).unwrap_or_else(|err| {
+                eprintln!("Warning: invalid markup in '{}': {}, shown as plain text", line, err);
+                StyledText::plain(line, text::base_style(&config))
+            })
+        })
+        .collect();
+    let gwstuff_config: Rc<Config> = Rc::new(config);
     let duration_timer = gwstuff_config.window.duration as u64;
 
     let (env, display, queue) =
-        new_default_environment!(Env, fields = [layer_shell: SimpleGlobal::new(),])
+        new_default_environment!(Env, fields = [layer_shell: SimpleGlobal::new(), xdg_output_manager: SimpleGlobal::new(),])
             .expect("Initial roundtrip failed!");
 
     let surfaces = Rc::new(RefCell::new(Vec::new()));
 
     let layer_shell = env.require_global::<zwlr_layer_shell_v1::ZwlrLayerShellV1>();
+    // Optional, without it the logical size of the outputs is computed from their scale factor
+    let xdg_output_manager = env.get_global::<zxdg_output_manager_v1::ZxdgOutputManagerV1>();
 
     let env_handle = env.clone();
     let surfaces_handle = Rc::clone(&surfaces);
     let output_handler = move |output: wl_output::WlOutput, info: &OutputInfo| {
 
-        let mut display_dim: (u32, u32) = (1, 1);
-        for &mode in info.modes.iter() {
-            if mode.is_current {
-                display_dim = (mode.dimensions.0 as u32, mode.dimensions.1 as u32);
-            }
-        }
+        let display_dim = logical_output_size(info);
 
         if info.obsolete {
             // an output has been removed, release it
@@ -484,23 +403,19 @@ fn main() {
             // an output has been created, construct a surface for it
             let surface = env_handle.create_surface().detach();
             let pool = env_handle.create_auto_pool().expect("Failed to create a memory pool!");
-            (*surfaces_handle.borrow_mut())
-                .push(
-                    (
-                        info.id, Surface::new(
-                                                //&output,
-                                                surface,
-                                                &layer_shell.clone(),
-                                                pool,
-                                                //(gwstuff_config.window.width, gwstuff_config.window.height),
-                                                display_dim,
-                                                //zwlr_layer_surface_v1::Anchor::from_raw(win_position.0.to_raw() | win_position.1.to_raw()).unwrap(), // TODO remove unwrap
-                                                //(gwstuff_config.margins.horizontal_percentage, gwstuff_config.margins.vertical_percentage),
-                                                Rc::clone(&gwstuff_config),
-                                                args.clone()
-                                             )
-                       )
-                    );
+            let mut new_surface = Surface::new(
+                surface,
+                &layer_shell.clone(),
+                pool,
+                display_dim,
+                Rc::clone(&gwstuff_config),
+                Rc::clone(&resources),
+                text.clone(),
+            );
+            if let Some(manager) = &xdg_output_manager {
+                new_surface.follow_xdg_output(manager, &output);
+            }
+            surfaces_handle.borrow_mut().push((info.id, new_surface));
         }
     };
 
@@ -0,0 +1,283 @@
+use std::ops::Range;
+
+use crate::color::Color;
+use crate::font::MAX_FONT_SIZE;
+
+/// Style of a part of the text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub color: Color,
+    /// Font size in points
+    pub size: f32,
+}
+
+/// Text with the style of each of its parts. The spans cover the whole text
+/// one after the other, there is always at least one (empty if the text is)
+#[derive(Debug, Clone)]
+pub struct StyledText {
+    pub text: String,
+    pub spans: Vec<(Range<usize>, Style)>,
+}
+
+impl StyledText {
+    pub fn plain(text: &str, style: Style) -> Self {
+        StyledText { text: text.to_string(), spans: vec![(0..text.len(), style)] }
+    }
+
+    /// Style of the character starting at `index`, the last one past the end of the text
+    pub fn style_at(&self, index: usize) -> Style {
+        self.spans
+            .iter()
+            .find(|(range, _)| range.contains(&index))
+            .or(self.spans.last())
+            .map(|(_, style)| *style)
+            .expect("A styled text has at least one span")
+    }
+
+    /// The spans inside `range`, cut to it
+    pub fn spans_in(&self, range: Range<usize>) -> impl Iterator<Item = (Range<usize>, Style)> + '_ {
+        self.spans.iter().filter_map(move |(span, style)| {
+            let start = span.start.max(range.start);
+            let end = span.end.min(range.end);
+            (start < end).then_some((start..end, *style))
+        })
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> StyledText {
+
+        let mut spans: Vec<(Range<usize>, Style)> = self
+            .spans_in(range.clone())
+            .map(|(span, style)| ((span.start - range.start)..(span.end - range.start), style))
+            .collect();
+        if spans.is_empty() {
+            spans.push((0..0, self.style_at(range.start)));
+        }
+
+        StyledText { text: self.text[range].to_string(), spans }
+    }
+
+    pub fn push_str(&mut self, text: &str, style: Style) {
+
+        if text.is_empty() {
+            return;
+        }
+        let start = self.text.len();
+        self.text.push_str(text);
+        let end = self.text.len();
+
+        match self.spans.last_mut() {
+            Some((range, last)) if range.start == range.end || *last == style => {
+                range.end = end;
+                *last = style;
+            }
+            _ => self.spans.push((start..end, style)),
+        }
+    }
+}
+
+/// Parse the Pango-like markup of `text`: `<b>`, `<i>`, `<u>`, `<span>` with the `color`
+/// and `size` (in points) attributes, the entities `&lt;` `&gt;` `&amp;` `&quot;` `&apos;`
+/// and the numeric character references (`&#65;`, `&#x41;`). The text outside of any tag has the `base` style
+pub fn parse(text: &str, base: Style) -> Result<StyledText, String> {
+
+    let mut styled = StyledText { text: String::new(), spans: vec![(0..0, base)] };
+    // The open tags with the style of the text inside them
+    let mut open: Vec<(String, Style)> = Vec::new();
+    let mut rest = text;
+
+    while let Some(index) = rest.find(['<', '&']) {
+        let style = open.last().map_or(base, |(_, style)| *style);
+        styled.push_str(&rest[..index], style);
+        rest = &rest[index..];
+
+        if rest.starts_with('&') {
+            let end = rest.find(';').ok_or_else(|| format!("unterminated entity '{}'", rest))?;
+            let entity = match &rest[1..end] {
+                "lt" => '<',
+                "gt" => '>',
+                "amp" => '&',
+                "quot" => '"',
+                "apos" => '\'',
+                other => character_reference(other).ok_or_else(|| format!("unknown entity '&{};'", other))?,
+            };
+            styled.push_str(entity.encode_utf8(&mut [0; 4]), style);
+            rest = &rest[(end + 1)..];
+            continue;
+        }
+
+        let end = rest.find('>').ok_or_else(|| format!("unterminated tag '{}'", rest))?;
+        let tag = &rest[1..end];
+        rest = &rest[(end + 1)..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            match open.pop() {
+                Some((open_name, _)) if open_name == name.trim() => (),
+                Some((open_name, _)) => return Err(format!("</{}> closes <{}>", name.trim(), open_name)),
+                None => return Err(format!("</{}> closes no tag", name.trim())),
+            }
+        } else {
+            let (name, style) = parse_tag(tag, style)?;
+            open.push((name, style));
+        }
+    }
+
+    if let Some((name, _)) = open.last() {
+        return Err(format!("<{}> is never closed", name));
+    }
+    styled.push_str(rest, base);
+
+    Ok(styled)
+}
+
+/// The character of a numeric character reference, `#65` or `#x41` without the `&` and the `;`
+fn character_reference(entity: &str) -> Option<char> {
+
+    let number = entity.strip_prefix('#')?;
+    let code = match number.strip_prefix(['x', 'X']) {
+        Some(hex) if hex.chars().all(|c| c.is_ascii_hexdigit()) => u32::from_str_radix(hex, 16).ok()?,
+        Some(_) => return None,
+        None if number.chars().all(|c| c.is_ascii_digit()) => number.parse().ok()?,
+        None => return None,
+    };
+    char::from_u32(code).filter(|c| *c != '\0')
+}
+
+/// Parse the content of an opening tag, returns its name and
+/// the style of the text inside it, starting from the `outer` one
+fn parse_tag(tag: &str, outer: Style) -> Result<(String, Style), String> {
+
+    let tag = tag.trim();
+    let (name, mut attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+    let mut style = outer;
+
+    match name {
+        "b" => style.bold = true,
+        "i" => style.italic = true,
+        "u" => style.underline = true,
+        "span" => (),
+        _ => return Err(format!("unknown tag <{}>", name)),
+    }
+
+    loop {
+        attributes = attributes.trim_start();
+        if attributes.is_empty() {
+            break;
+        }
+        if name != "span" {
+            return Err(format!("<{}> has no attributes", name));
+        }
+
+        let (key, value) = attributes.split_once('=').ok_or_else(|| format!("invalid attribute '{}'", attributes))?;
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'').ok_or_else(|| format!("unquoted value of '{}'", key.trim()))?;
+        let (value, rest) = value[1..].split_once(quote).ok_or_else(|| format!("unterminated value of '{}'", key.trim()))?;
+        attributes = rest;
+
+        match key.trim() {
+            "color" => style.color = value.parse()?,
+            "size" => {
+                style.size = value.parse().ok().filter(|size: &f32| *size > 0.0).ok_or_else(|| format!("invalid size '{}'", value))?;
+                if style.size > MAX_FONT_SIZE {
+                    return Err(format!("size {} is bigger than the maximum size {}", value, MAX_FONT_SIZE));
+                }
+            }
+            other => return Err(format!("unknown attribute '{}' of <span>", other)),
+        }
+    }
+
+    Ok((name.to_string(), style))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Style = Style {
+        bold: false,
+        italic: false,
+        underline: false,
+        color: Color { r: 0, g: 0, b: 0, a: 255 },
+        size: 12.0,
+    };
+
+    /// The text of each span with its style
+    fn spans(styled: &StyledText) -> Vec<(&str, Style)> {
+        styled.spans.iter().map(|(range, style)| (&styled.text[range.clone()], *style)).collect()
+    }
+
+    #[test]
+    fn plain_text_has_the_base_style() {
+        let styled = parse("Volume 50%", BASE).unwrap();
+        assert_eq!(spans(&styled), vec![("Volume 50%", BASE)]);
+    }
+
+    #[test]
+    fn nested_tags_add_up() {
+        let styled = parse("<b>bold <i>both</i></b> <u>under</u>", BASE).unwrap();
+        let bold = Style { bold: true, ..BASE };
+        assert_eq!(styled.text, "bold both under");
+        assert_eq!(spans(&styled), vec![
+            ("bold ", bold),
+            ("both", Style { italic: true, ..bold }),
+            (" ", BASE),
+            ("under", Style { underline: true, ..BASE }),
+        ]);
+    }
+
+    #[test]
+    fn entities_are_replaced() {
+        let styled = parse("&lt;b&gt; &amp; &quot;x&quot; &apos;", BASE).unwrap();
+        assert_eq!(spans(&styled), vec![("<b> & \"x\" '", BASE)]);
+    }
+
+    #[test]
+    fn numeric_character_references() {
+        assert_eq!(parse("&#65;&#x42;&#X43;&#x1F600;", BASE).unwrap().text, "ABC\u{1F600}");
+        for text in ["&#xD800;", "&#x110000;", "&#0;", "&#x;", "&#;", "&#+65;", "&#x-41;"] {
+            assert!(parse(text, BASE).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_or_unterminated_entities_are_errors() {
+        assert!(parse("&nbsp;", BASE).is_err());
+        assert!(parse("a & b", BASE).is_err());
+    }
+
+    #[test]
+    fn unclosed_or_mismatched_tags_are_errors() {
+        for text in ["<b>bold", "<b><i>x</b></i>", "x</b>", "<b", "<blink>x</blink>"] {
+            assert!(parse(text, BASE).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn span_attributes() {
+        let styled = parse("<span color='#ff0000' size=\"20\">big</span> small", BASE).unwrap();
+        let red = Color { r: 255, g: 0, b: 0, a: 255 };
+        assert_eq!(spans(&styled), vec![("big", Style { color: red, size: 20.0, ..BASE }), (" small", BASE)]);
+        let styled = parse(&format!("<span size='{}'>x</span>", MAX_FONT_SIZE), BASE).unwrap();
+        assert_eq!(styled.style_at(0).size, MAX_FONT_SIZE);
+    }
+
+    #[test]
+    fn invalid_span_attributes_are_errors() {
+        let texts = [
+            "<span weight='bold'>x</span>",
+            "<span size=20>x</span>",
+            "<span size='20>x</span>",
+            "<span size='0'>x</span>",
+            "<span size='NaN'>x</span>",
+            "<span size='inf'>x</span>",
+            "<span size='1e9'>x</span>",
+            "<span color='nope'>x</span>",
+            "<b color='red'>x</b>",
+        ];
+        for text in texts {
+            assert!(parse(text, BASE).is_err(), "{}", text);
+        }
+    }
+}
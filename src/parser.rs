@@ -1,145 +1,1104 @@
 use serde_derive::Deserialize;
 
-use toml;
-use whoami;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::color::Color;
+use crate::font::MAX_FONT_SIZE;
+use crate::xdg::{xdg_dir, xdg_dirs};
+use toml::Value;
+use toml_edit::ImDocument;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub window:  WindowProps,
     pub margins: MarginProps,
     pub font:    FontProps,
+    pub icon:    IconProps,
+    pub progress: ProgressProps,
 }
 
-#[derive(Debug, Deserialize, Copy, Clone)]
-pub enum Placement{
+/// Alignment of the box along one axis of the output
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AxisAlign {
+    Start,
+    Center,
+    End,
+}
+
+/// One of the nine points of the output the box is attached to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
     Top,
-    Bottom,
+    TopRight,
     Left,
+    Center,
     Right,
-    CenterVertical,
-    CenterHorizontal,
+    BottomLeft,
+    Bottom,
+    BottomRight,
 }
 
-impl Placement{
-    pub fn to_raw(self) -> u32{
-        match self{
-            Placement::Top => 1,
-            Placement::Bottom => 2,
-            Placement::Left => 4,
-            Placement::Right => 8,
-            Placement::CenterVertical => 3,
-            Placement::CenterHorizontal => 12,
+impl Anchor {
+    const NAMES: [(&'static str, Anchor); 9] = [
+        ("top-left", Anchor::TopLeft),
+        ("top", Anchor::Top),
+        ("top-right", Anchor::TopRight),
+        ("left", Anchor::Left),
+        ("center", Anchor::Center),
+        ("right", Anchor::Right),
+        ("bottom-left", Anchor::BottomLeft),
+        ("bottom", Anchor::Bottom),
+        ("bottom-right", Anchor::BottomRight),
+    ];
+
+    pub fn horizontal(self) -> AxisAlign {
+        match self {
+            Anchor::TopLeft | Anchor::Left | Anchor::BottomLeft => AxisAlign::Start,
+            Anchor::Top | Anchor::Center | Anchor::Bottom => AxisAlign::Center,
+            Anchor::TopRight | Anchor::Right | Anchor::BottomRight => AxisAlign::End,
         }
     }
+
+    pub fn vertical(self) -> AxisAlign {
+        match self {
+            Anchor::TopLeft | Anchor::Top | Anchor::TopRight => AxisAlign::Start,
+            Anchor::Left | Anchor::Center | Anchor::Right => AxisAlign::Center,
+            Anchor::BottomLeft | Anchor::Bottom | Anchor::BottomRight => AxisAlign::End,
+        }
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Anchor::NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, anchor)| *anchor)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Anchor::NAMES.iter().map(|(name, _)| *name).collect();
+                format!("unknown position '{}', expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Length in pixels or in percentage of a reference size (e.g. the output width)
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[serde(try_from = "LengthValue")]
+pub enum Length {
+    Px(i32),
+    Percent(f32),
 }
 
+impl Length {
+    pub fn to_px(self, total: u32) -> i32 {
+        match self {
+            Length::Px(px) => px,
+            Length::Percent(percentage) => (percentage * total as f32 / 100.0).round() as i32,
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    /// Parse "20", "20px" or "5%"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("invalid length '{}', expected pixels (e.g. 20 or '20px') or a percentage (e.g. '5%')", s);
+
+        match s.strip_suffix('%') {
+            Some(percentage) => percentage.trim().parse().map(Length::Percent).map_err(|_| invalid()),
+            None => s.strip_suffix("px").unwrap_or(s).trim().parse().map(Length::Px).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Lengths can be written in the config as integer pixels or as strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LengthValue {
+    Px(i32),
+    Str(String),
+}
+
+impl TryFrom<LengthValue> for Length {
+    type Error = String;
+
+    fn try_from(value: LengthValue) -> Result<Self, Self::Error> {
+        match value {
+            LengthValue::Px(px) => Ok(Length::Px(px)),
+            LengthValue::Str(s) => s.parse(),
+        }
+    }
+}
+
+/// Position of the box: the anchor point and an optional offset from it.
+/// The offset moves the box away from the anchored edges, for a centered
+/// axis a positive offset moves it right (x) or down (y)
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Position {
+    pub anchor: Anchor,
+    pub x: Length,
+    pub y: Length,
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    /// Parse "<anchor> [<x> <y>]", e.g. "top-right" or "bottom 0 5%"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        let anchor: Anchor = parts.next().unwrap_or_default().parse()?;
+        let offsets = parts.map(Length::from_str).collect::<Result<Vec<Length>, String>>()?;
+
+        match offsets[..] {
+            [] => Ok(Position { anchor, x: Length::Px(0), y: Length::Px(0) }),
+            [x, y] => Ok(Position { anchor, x, y }),
+            _ => Err(format!("invalid position '{}', expected '<position>' or '<position> <x> <y>'", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Position {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parsed with `FromStr` in the config too, to accept the same values as the command line
 #[derive(Debug, Deserialize, Copy, Clone)]
+#[serde(try_from = "String")]
 pub enum TextAlignment {
     Center, 
     Left, 
     Right,
 }
 
+impl FromStr for TextAlignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "center" => Ok(TextAlignment::Center),
+            "left"   => Ok(TextAlignment::Left),
+            "right"  => Ok(TextAlignment::Right),
+            _ => Err(format!("unknown alignment '{}', expected one of: center, left, right", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for TextAlignment {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Vertical position of the content when the box is taller than it,
+/// the capitalized names of the old configs are still accepted
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerticalAlignment {
+    #[serde(alias = "Top")]
+    Top,
+    #[serde(alias = "Center")]
+    Center,
+    #[serde(alias = "Bottom")]
+    Bottom,
+}
+
+/// What to do with the text that doesn't fit in the box
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Cut the text at the paddings
+    Clip,
+    /// Cut the lines (and the exceeding lines) showing an ellipsis
+    Ellipsis,
+    /// Break the lines at the whitespaces, or inside the words longer than a line
+    Wrap,
+    /// Reduce the font size until the text fits
+    Shrink,
+}
+
+/// Weight of the font, from 1 to 1000 as in CSS (400 is normal, 700 bold)
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(try_from = "FontWeightValue")]
+pub struct FontWeight(pub u32);
+
+impl FromStr for FontWeight {
+    type Err = String;
+
+    /// Parse a number or one of the names: thin, extralight, light, normal (or regular),
+    /// medium, semibold, bold, extrabold, black
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weight = match s.trim().to_lowercase().as_str() {
+            "thin" => 100,
+            "extralight" => 200,
+            "light" => 300,
+            "normal" | "regular" => 400,
+            "medium" => 500,
+            "semibold" => 600,
+            "bold" => 700,
+            "extrabold" => 800,
+            "black" => 900,
+            other => other.parse().map_err(|_| format!("invalid font weight '{}'", s))?,
+        };
+        FontWeight::try_from(FontWeightValue::Number(weight))
+    }
+}
+
+/// Weights can be written in the config as numbers or as names
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FontWeightValue {
+    Number(u32),
+    Str(String),
+}
+
+impl TryFrom<FontWeightValue> for FontWeight {
+    type Error = String;
+
+    fn try_from(value: FontWeightValue) -> Result<Self, Self::Error> {
+        match value {
+            FontWeightValue::Number(weight) if (1..=1000).contains(&weight) => Ok(FontWeight(weight)),
+            FontWeightValue::Number(weight) => Err(format!("font weight {} is not between 1 and 1000", weight)),
+            FontWeightValue::Str(s) => s.parse(),
+        }
+    }
+}
+
+/// Slant of the font
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum FontStyle {
+    Normal,
+    Italic,
+    Oblique,
+}
+
+/// Width of the font, from the narrowest to the widest
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum FontStretch {
+    UltraCondensed,
+    ExtraCondensed,
+    Condensed,
+    SemiCondensed,
+    Normal,
+    SemiExpanded,
+    Expanded,
+    ExtraExpanded,
+    UltraExpanded,
+}
+
+impl FontStretch {
+    /// Width in percentage of the normal one
+    pub fn percentage(self) -> u32 {
+        match self {
+            FontStretch::UltraCondensed => 50,
+            FontStretch::ExtraCondensed => 63,
+            FontStretch::Condensed => 75,
+            FontStretch::SemiCondensed => 87,
+            FontStretch::Normal => 100,
+            FontStretch::SemiExpanded => 113,
+            FontStretch::Expanded => 125,
+            FontStretch::ExtraExpanded => 150,
+            FontStretch::UltraExpanded => 200,
+        }
+    }
+}
+
+/// Color of a gradient at `position`, a percentage of the length of the gradient
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[serde(from = "ColorStopValue")]
+pub struct ColorStop {
+    pub color: Color,
+    /// Without a position the stop is halfway between its neighbours, the first one at 0 and the last at 100
+    pub position: Option<f32>,
+}
+
+/// A stop can be written as a color alone or as a table with its position
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorStopValue {
+    Color(Color),
+    Stop { color: Color, position: Option<f32> },
+}
+
+impl From<ColorStopValue> for ColorStop {
+    fn from(value: ColorStopValue) -> Self {
+        match value {
+            ColorStopValue::Color(color) => ColorStop { color, position: None },
+            ColorStopValue::Stop { color, position } => ColorStop { color, position },
+        }
+    }
+}
+
+/// Background of the box made of colors blending into each other
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Gradient {
+    /// Along a line through the center of the box, `angle` is in degrees
+    /// clockwise from the top, as in CSS (90 goes from left to right)
+    Linear { angle: f32, stops: Vec<ColorStop> },
+    /// Circles around `center` (x, y) up to the farthest corner of the box, the percentages are of its size
+    Radial {
+        #[serde(default = "Gradient::default_center")]
+        center: (Length, Length),
+        stops: Vec<ColorStop>,
+    },
+}
+
+impl Gradient {
+    fn default_center() -> (Length, Length) {
+        (Length::Percent(50.0), Length::Percent(50.0))
+    }
+
+    pub fn stops(&self) -> &[ColorStop] {
+        match self {
+            Gradient::Linear { stops, .. } | Gradient::Radial { stops, .. } => stops,
+        }
+    }
+}
+
+/// How the background image is fitted in the box
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFit {
+    /// Scaled to fill the box, cutting what overflows
+    Cover,
+    /// Scaled to fit inside the box, centered
+    Contain,
+    /// Scaled to the size of the box, ignoring its aspect ratio
+    Stretch,
+    /// Repeated at its own size from the top left corner
+    Tile,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct WindowProps{
-    pub background_color: u32,
+    pub background_color: Color,
+    /// Gradient drawn instead of `background_color`
+    pub background: Option<Gradient>,
+    /// Percentage applied over the alpha of the background
     pub background_opacity: u32,
-    win_position_str: String,
+    /// PNG or JPEG drawn over the background, inside the border
+    pub background_image: Option<String>,
+    pub fit: ImageFit,
+    /// Percentage applied over the alpha of `background_image`
+    pub image_opacity: u32,
+    pub position: Position,
+    /// Radius of the rounded corners of the box, 0 for square corners
+    pub corner_radius: u32,
+    pub border_width: u32,
+    pub border_color: Color,
+    /// Shadow drawn below the box, none if transparent
+    pub shadow_color: Color,
+    /// Offset (x, y) in pixels of the shadow from the box
+    pub shadow_offset: (i32, i32),
+    /// Distance in pixels over which the edge of the shadow fades out
+    pub shadow_blur_radius: u32,
+    /// Space between the border and the content
     pub vertical_padding: u32,
     pub horizontal_padding: u32,
-    pub win_position: Option<(Placement, Placement)>,
+    /// Fixed size of the box, otherwise it's the size of the content (paddings included)
+    pub width: Option<Length>,
+    pub height: Option<Length>,
+    pub min_width: Option<Length>,
+    pub max_width: Option<Length>,
+    pub min_height: Option<Length>,
+    pub max_height: Option<Length>,
+    pub vertical_alignment: VerticalAlignment,
+    pub overflow: Overflow,
     pub duration: u32,
 }
 impl WindowProps{
-    pub fn calc_win_position(&mut self) {
-
-        let mut full_placement = (Placement::CenterVertical, Placement::CenterHorizontal);
-
-
-        // Get string to remove whitespaces
-        let mut position_stripped_spaces: String = self.win_position_str.clone();
-        // Remove whitespaces
-        position_stripped_spaces.retain(|c| !c.is_whitespace());
-
-        // Set the properties
-        position_stripped_spaces.split(",").for_each(|position| {
-            match position{
-                "CenterHorizontal" => full_placement.0 = Placement::CenterHorizontal,
-                "CenterVertical"   => full_placement.1 = Placement::CenterVertical,
-                "Left"              => full_placement.0 = Placement::Left,
-                "Right"             => full_placement.0 = Placement::Right,
-                "Top"               => full_placement.1 = Placement::Top,
-                "Bottom"            => full_placement.1 = Placement::Bottom,
-                _ => ()
-            }
-        });
+    /// Background color with the opacity applied, used without a gradient
+    pub fn solid_background(&self) -> Color {
+        self.background_color.with_opacity(self.background_opacity)
+    }
 
-        self.win_position = Some(full_placement.clone());
+    /// Space between the edges of the box and the content: the border and the paddings inside it
+    pub fn content_inset(&self) -> (u32, u32) {
+        (self.border_width + self.horizontal_padding, self.border_width + self.vertical_padding)
     }
 }
 
+/// Space between the box and the edges of the output, percentages are
+/// relative to the output width for left and right, to the height for top and bottom
 #[derive(Debug, Deserialize)]
 pub struct MarginProps{
-    pub vertical_percentage:   u8,
-    pub horizontal_percentage: u8,
+    pub top:    Length,
+    pub right:  Length,
+    pub bottom: Length,
+    pub left:   Length,
+    /// Deprecated, percentage of the height for `top` and `bottom`
+    pub vertical_percentage:   Option<f32>,
+    /// Deprecated, percentage of the width for `left` and `right`
+    pub horizontal_percentage: Option<f32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct FontProps{
+    /// Family of the font, looked up with fontconfig
     pub name:  String,
+    /// Font file (.ttf, .otf) used instead of looking up `name`
+    pub path:  Option<String>,
+    pub weight:  FontWeight,
+    pub style:   FontStyle,
+    pub stretch: FontStretch,
+    /// Fonts tried in order for the characters missing in `name`,
+    /// before asking fontconfig for any font that has them
+    pub fallback: Vec<String>,
     pub size:  f32,
-    pub color: u32,
+    pub color: Color,
     pub intra_line: f32,
-    pub text_alignment: TextAlignment
+    pub text_alignment: TextAlignment,
+    /// Parse the text as markup: <b>, <i>, <u> and <span color="..." size="...">
+    pub markup: bool,
+    /// Blend the glyphs with the background in linear light instead of sRGB,
+    /// so that light text on dark backgrounds is not thinner than dark text on light ones
+    pub linear_blending: bool,
+    /// Darkening of the antialiased edges of the glyphs, 0.0 leaves them as rasterized
+    /// and higher values make the strokes look heavier
+    pub contrast: f32,
+}
+
+/// Side of the text the icon is drawn on
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IconPosition {
+    Left,
+    Right,
+    Top,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IconProps {
+    /// PNG, JPEG or SVG file shown next to the text, or the name of an icon of the icon theme
+    pub path: Option<String>,
+    /// Icon theme the names are looked up in, the one of the GTK settings if not set
+    pub theme: Option<String>,
+    /// Side of the square the icon is scaled to fit in, in pixels
+    pub size: u32,
+    pub position: IconPosition,
+    /// Space between the icon and the text, in pixels
+    pub spacing: u32,
+    /// Alignment of the icon and the text to each other when they are side by side
+    pub vertical_alignment: VerticalAlignment,
+}
+
+/// Side of the text the progress bar is drawn on
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressPosition {
+    Bottom,
+    Right,
+}
+
+/// Shape the progress is drawn as
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressShape {
+    Bar,
+    /// A ring filled clockwise from the top, `length` wide and `height` thick
+    Ring,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ProgressProps {
+    /// Percentage shown by the bar, up to `MAX_PROGRESS`, no bar if not set
+    pub value: Option<u32>,
+    pub position: ProgressPosition,
+    pub shape: ProgressShape,
+    /// Length of the bar beside the text, and the least one below it, in pixels
+    pub length: u32,
+    /// Thickness of the bar, in pixels
+    pub height: u32,
+    pub corner_radius: u32,
+    /// Space between the bar and the text, in pixels
+    pub spacing: u32,
+    pub track_color: Color,
+    pub fill_color: Color,
+    /// Color of the part above 100%, drawn again from the start of the bar.
+    /// Without it the bar stops at 100%
+    pub overflow_color: Option<Color>,
+}
+
+impl ProgressProps {
+    /// Width and height of the bar, a ring takes a square
+    pub fn size(&self) -> (u32, u32) {
+        match self.shape {
+            ProgressShape::Bar => (self.length, self.height),
+            ProgressShape::Ring => (self.length, self.length),
+        }
+    }
+}
 
+/// Largest progress value, at 200% the overflowing part covers the whole bar
+pub const MAX_PROGRESS: u32 = 200;
 
+/// Built-in config, every field missing in the user configs is taken from here
 static DEFAULT_CONFIG: &str = r#"
         [window]
-        width  = 600
-        height = 600
-        background_color = 0x262626
-        background_opacity= 80
+        # 0xRRGGBB, '#rrggbb', '#rrggbbaa', 'rgba(r, g, b, a)' or a color name
+        background_color = '#262626cc'
+        # a gradient instead of background_color, the stops are colors or
+        # { color = '...', position = <percentage> } tables:
+        # background = { type = 'linear', angle = 90, stops = ['#3a1c71', '#ffaf7b'] }
+        # background = { type = 'radial', center = ['50%', '0%'], stops = ['#3a1c71', '#ffaf7b'] }
+        background_opacity = 100
+
+        # a PNG or JPEG drawn over the background, fitted in the box with
+        # cover, contain, stretch or tile
+        # background_image = '~/Pictures/notification.png'
+        fit = 'cover'
+        image_opacity = 100
+
+        # top-left, top, top-right, left, center, right, bottom-left, bottom or bottom-right,
+        # optionally followed by the x and y offsets in pixels or percentage (e.g. 'top-right 20 5%')
+        position = 'center'
 
-        # Possible values are {CenterVertical, CenterHorizontal, Top, Bottom, Left, Right}
-        win_position_str = 'CenterVertical, CenterHorizontal'
+        # pixels, the corners are antialiased and transparent outside the curve
+        corner_radius = 0
+        border_width  = 0
+        border_color  = '#808080'
 
+        # soft shadow below the box, not drawn while its color is transparent.
+        # The offset is [x, y] in pixels, the surface grows to make room for it
+        shadow_color       = '#00000000'
+        shadow_offset      = [0, 0]
+        shadow_blur_radius = 0
+
+        # space between the border and the text, in pixels
         vertical_padding   = 5
         horizontal_padding = 5
 
+        # The box is as big as its content, unless a fixed size is specified with width and height.
+        # Optional bounds are min_width, max_width, min_height and max_height.
+        # The sizes are in pixels (e.g. 300 or '300px') or percentage of the screen (e.g. '50%')
+        # width  = 600
+        # height = 600
+
+        # Position of the content when the box is taller than it: {top, center, bottom}
+        vertical_alignment = 'center'
+
+        # What to do with the text that doesn't fit in the box: {clip, ellipsis, wrap, shrink}.
+        # With wrap, the lines longer than max_width (or the screen) continue on the next line
+        overflow = 'wrap'
+
         # milliseconds                        
         duration = 5000 
 
+        # pixels (e.g. 20 or '20px') or percentage of the screen (e.g. '5%')
         [margins]
-        vertical_percentage   = 5
-        horizontal_percentage = 5
+        top    = '5%'
+        right  = '5%'
+        bottom = '5%'
+        left   = '5%'
 
         [font]
-        name  = 'Roboto Condensed'
+        # A family name or a generic one: 'sans-serif', 'serif', 'monospace'
+        name  = 'sans-serif'
+        # path = '/usr/share/fonts/TTF/RobotoCondensed-Regular.ttf'
+        # 1 to 1000, or thin, extralight, light, normal, medium, semibold, bold, extrabold, black
+        weight  = 'normal'
+        # normal, italic or oblique
+        style   = 'normal'
+        # ultra-condensed, extra-condensed, condensed, semi-condensed, normal,
+        # semi-expanded, expanded, extra-expanded, ultra-expanded
+        stretch = 'normal'
+        fallback = []
         size  = 30
-        color = 0x808080
+        color = '#808080'
         intra_line = 1.0
-        text_alignment = 'Center'
+        text_alignment = 'center'
+        markup = false
+        linear_blending = false
+        contrast = 0.0
+
+        [icon]
+        # a PNG, JPEG or SVG file or the name of an icon in the icon theme, also set with --icon
+        # path = '/usr/share/icons/hicolor/48x48/apps/firefox.png'
+        # theme where the icon names are looked up, hicolor is searched after it
+        # theme = 'Adwaita'
+        # pixels, the icon is scaled to fit in a square this big
+        size = 48
+        # left, right or top of the text
+        position = 'left'
+        spacing = 10
+        # alignment of the icon and the text beside it: {top, center, bottom}
+        vertical_alignment = 'center'
+
+        [progress]
+        # percentage shown by the bar, also set with --progress
+        # value = 50
+        # bottom or right of the text
+        position = 'bottom'
+        # bar or ring, a ring is length wide and height thick
+        shape = 'bar'
+        # pixels, the bar below the text is as long as the text if that's longer
+        length = 200
+        height = 8
+        corner_radius = 4
+        spacing = 10
+        track_color = '#ffffff33'
+        fill_color = '#ffffff'
+        # color of the part above 100%, the bar stops at 100% if not set
+        # overflow_color = '#ff5050'
     "#;
 
 
-pub fn init_toml_config(config_name: Option<String>) -> Config {
+/// Position of a key inside a config file, lines and columns start from 1
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
 
-    let mut config: Config;
+impl Location {
+    fn from_offset(path: &Path, source: &str, offset: usize) -> Self {
+        let before = &source[..offset.min(source.len())];
+        Location {
+            path: path.to_path_buf(),
+            line: before.matches('\n').count() + 1,
+            column: before.rsplit('\n').next().map_or(0, |line| line.chars().count()) + 1,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path.display(), self.line, self.column)
+    }
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths.iter().map(|path| path.display().to_string()).collect::<Vec<String>>().join(", ")
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("config '{name}' not found, searched in: {}", display_paths(searched))]
+    NotFound { name: String, searched: Vec<PathBuf> },
+
+    #[error("cannot read {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{location}: {message}")]
+    Syntax { location: Location, message: String },
+
+    #[error("{}invalid value for `{key}`: {message}", location.as_ref().map(|location| format!("{}: ", location)).unwrap_or_default())]
+    InvalidValue { location: Option<Location>, key: String, message: String },
+
+    #[error("cycle in the config inheritance: {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<String>>().join(" -> "))]
+    Cycle(Vec<PathBuf>),
+}
+
+/// Name of the config loaded when none is specified
+static DEFAULT_CONFIG_NAME: &str = "default";
+
+/// `path` with a leading `~/` replaced by the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Directories where the configs are searched, in order of priority,
+/// following the XDG base directory specification
+pub fn config_dirs() -> Vec<PathBuf> {
+
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    dirs.extend(xdg_dir("XDG_CONFIG_HOME", ".config"));
+    dirs.extend(xdg_dirs("XDG_CONFIG_DIRS", "/etc/xdg"));
+
+    // The system wide configs are always looked up, even with a custom $XDG_CONFIG_DIRS
+    let system_fallback = PathBuf::from("/etc/xdg");
+    if !dirs.contains(&system_fallback) {
+        dirs.push(system_fallback);
+    }
+
+    dirs.into_iter().map(|dir| dir.join("gwstuff")).collect()
+}
+
+/// All the `<name>.toml` in the config directories, in order of priority
+fn find_named_configs(name: &str) -> Vec<PathBuf> {
+    config_dirs()
+        .into_iter()
+        .map(|dir| dir.join(format!("{}.toml", name)))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Find the first `<name>.toml` in the config directories
+fn find_named_config(name: &str) -> Option<PathBuf> {
+    find_named_configs(name).into_iter().next()
+}
+
+/// A config reference is a path to a file if it contains a '/' or ends with '.toml',
+/// otherwise it's the name of a config in the config directories
+fn is_config_path(config: &str) -> bool {
+    let path = Path::new(config);
+    path.components().count() > 1 || path.extension().is_some_and(|ext| ext == "toml")
+}
 
-    if let Some(conf_name) = config_name{
-        // If the config name is specified, open it in the .conf directory and parse it
-        let path: String = format!("{}{}{}{}{}", "/home/", whoami::username(), "/.config/gwstuff/", conf_name, ".toml");
-        config = toml::from_str(&fs::read_to_string(path).expect("Invalid file path")).expect("Invalid TOML config file");
+/// Resolve the config given by the user: a path to a file (absolute or relative to
+/// the current directory) or the name of a config in the config directories
+pub fn find_config_file(config: &str) -> Option<PathBuf> {
+    if is_config_path(config) {
+        Some(PathBuf::from(config)).filter(|path| path.is_file())
+    } else {
+        find_named_config(config)
     }
-    else{
-        // If no filename is given, load default config
-        config = toml::from_str(DEFAULT_CONFIG).expect("Invalid DEFAULT_CONFIG");
+}
+
+/// Resolve the config extended by the one at `child`, relative paths
+/// are relative to the directory containing `child`. A name is searched after
+/// `child` if it's one of the configs with that name, so that e.g. the user
+/// `default.toml` can extend the system wide one with `extends = "default"`
+fn find_parent_config(child: &Path, parent: &str) -> Option<PathBuf> {
+    if is_config_path(parent) {
+        let dir = child.parent().unwrap_or_else(|| Path::new("."));
+        return Some(dir.join(parent)).filter(|path| path.is_file());
+    }
+
+    let configs = find_named_configs(parent);
+    let child = child.canonicalize().ok();
+    match configs.iter().position(|config| config.canonicalize().ok() == child) {
+        Some(index) => configs.into_iter().nth(index + 1),
+        None => configs.into_iter().next(),
+    }
+}
+
+/// A config file parsed as a generic TOML value,
+/// the source is kept to locate the keys when reporting errors
+struct ConfigLayer {
+    path: PathBuf,
+    source: String,
+    value: Value,
+}
+
+impl ConfigLayer {
+
+    fn parse(path: &Path) -> Result<Self, ConfigError> {
+
+        let source = fs::read_to_string(path).map_err(|source| ConfigError::Io { path: path.to_path_buf(), source })?;
+
+        let table: toml::Table = source.parse().map_err(|err: toml::de::Error| ConfigError::Syntax {
+            location: Location::from_offset(path, &source, err.span().map_or(0, |span| span.start)),
+            message: err.message().trim().replace('\n', ", "),
+        })?;
+
+        Ok(ConfigLayer { path: path.to_path_buf(), source, value: Value::Table(table) })
+    }
+
+    /// Check if the dotted `key` (e.g. "font.color") is set in this config
+    fn contains_key(&self, key: &str) -> bool {
+        key_segments(key)
+            .try_fold(&self.value, |value, segment| value.get(segment))
+            .is_some()
+    }
+
+    /// Find where the dotted `key` is written in this config
+    fn locate(&self, key: &str) -> Option<Location> {
+
+        let document = ImDocument::parse(self.source.as_str()).ok()?;
+        let mut table: &dyn toml_edit::TableLike = document.as_table();
+        let mut segments = key_segments(key).peekable();
+
+        while let Some(segment) = segments.next() {
+            let (key, item) = table.get_key_value(segment)?;
+            if segments.peek().is_none() {
+                return Some(Location::from_offset(&self.path, &self.source, key.span()?.start));
+            }
+            table = item.as_table_like()?;
+        }
+        None
+    }
+
+    /// Error for an invalid value of the dotted `key` set in this config
+    fn invalid_value(&self, key: &str, message: String) -> ConfigError {
+        ConfigError::InvalidValue { location: self.locate(key), key: key.to_string(), message }
+    }
+}
+
+/// Split a dotted key in its table keys, array indices (e.g. "[0]") are ignored
+fn key_segments(key: &str) -> impl Iterator<Item = &str> {
+    key.split('.').map(|segment| segment.split('[').next().unwrap_or(segment))
+}
+
+/// Find the last layer that sets `key`, the one its value comes from
+fn find_layer<'a>(layers: &'a [ConfigLayer], key: &str) -> Option<&'a ConfigLayer> {
+    layers.iter().rev().find(|layer| layer.contains_key(key))
+}
+
+/// Parse the config at `path` and, before it, all the configs it `extends`.
+/// The layers are pushed from the most generic one to `path` itself,
+/// `chain` holds the configs currently being loaded to detect cycles
+fn load_config_layers(path: &Path, chain: &mut Vec<PathBuf>, layers: &mut Vec<ConfigLayer>) -> Result<(), ConfigError> {
+
+    let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    if chain.contains(&canonical_path) {
+        chain.push(canonical_path);
+        return Err(ConfigError::Cycle(chain.clone()));
     }
+    chain.push(canonical_path);
 
-    config.window.calc_win_position();
+    let mut layer = ConfigLayer::parse(path)?;
 
-    config
+    // `extends` is not part of the Config, it's consumed here
+    if let Some(parent) = layer.value.get("extends").cloned() {
+        let parent = parent.as_str().ok_or_else(|| {
+            layer.invalid_value("extends", "expected the name or the path of a config".to_string())
+        })?;
+        let parent_path = find_parent_config(path, parent).ok_or_else(|| {
+            layer.invalid_value("extends", format!("config '{}' not found", parent))
+        })?;
+        load_config_layers(&parent_path, chain, layers)?;
+
+        if let Some(table) = layer.value.as_table_mut() {
+            table.remove("extends");
+        }
+    }
+
+    chain.pop();
+    layers.push(layer);
+    Ok(())
+}
+
+/// Recursively merge `overlay` into `base`: tables are merged key by key,
+/// every other value in `overlay` replaces the one in `base`
+fn merge_values(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_values(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl Config {
+    /// Check the ranges of the values, returns the key of the first invalid one and the reason
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+
+        let percentage = |key: &'static str, value: f32| {
+            if !(0.0..=100.0).contains(&value) {
+                Err((key, format!("{} is not a percentage between 0 and 100", value)))
+            } else {
+                Ok(())
+            }
+        };
+        let length = |key: &'static str, value: Length| match value {
+            Length::Percent(value) => percentage(key, value),
+            Length::Px(_) => Ok(()),
+        };
+
+        percentage("window.background_opacity", self.window.background_opacity as f32)?;
+        percentage("window.image_opacity", self.window.image_opacity as f32)?;
+        let window = &self.window;
+        if let Some(gradient) = &window.background {
+            if gradient.stops().is_empty() {
+                return Err(("window.background.stops", "a gradient needs at least one color".to_string()));
+            }
+            for position in gradient.stops().iter().filter_map(|stop| stop.position) {
+                percentage("window.background.stops", position)?;
+            }
+            if let Gradient::Radial { center, .. } = gradient {
+                length("window.background.center", center.0)?;
+                length("window.background.center", center.1)?;
+            }
+        }
+        let optional_length = |key: &'static str, value: Option<Length>| value.map_or(Ok(()), |value| length(key, value));
+        // A box 0 pixels wide or high has nothing to draw on
+        let optional_size = |key: &'static str, value: Option<Length>| match value {
+            Some(Length::Px(px)) if px <= 0 => Err((key, format!("{} is not a positive size", px))),
+            Some(Length::Percent(percentage)) if percentage <= 0.0 => Err((key, format!("{}% is not a positive size", percentage))),
+            _ => optional_length(key, value),
+        };
+        optional_size("window.width", window.width)?;
+        optional_size("window.height", window.height)?;
+        optional_length("window.min_width", window.min_width)?;
+        optional_size("window.max_width", window.max_width)?;
+        optional_length("window.min_height", window.min_height)?;
+        optional_size("window.max_height", window.max_height)?;
+
+        // Checked before the sides they are copied to, to report the key that was written
+        if let Some(value) = self.margins.vertical_percentage {
+            percentage("margins.vertical_percentage", value)?;
+        }
+        if let Some(value) = self.margins.horizontal_percentage {
+            percentage("margins.horizontal_percentage", value)?;
+        }
+        length("margins.top", self.margins.top)?;
+        length("margins.right", self.margins.right)?;
+        length("margins.bottom", self.margins.bottom)?;
+        length("margins.left", self.margins.left)?;
+
+        if !(self.font.size.is_finite() && self.font.size > 0.0) {
+            return Err(("font.size", format!("{} is not a positive size", self.font.size)));
+        }
+        if self.font.size > MAX_FONT_SIZE {
+            return Err(("font.size", format!("{} is bigger than the maximum size {}", self.font.size, MAX_FONT_SIZE)));
+        }
+        if !(self.font.intra_line.is_finite() && self.font.intra_line >= 0.0) {
+            return Err(("font.intra_line", format!("{} is not a positive space", self.font.intra_line)));
+        }
+        if !(self.font.contrast.is_finite() && self.font.contrast >= 0.0) {
+            return Err(("font.contrast", format!("{} is not a positive contrast", self.font.contrast)));
+        }
+        if self.icon.size == 0 {
+            return Err(("icon.size", "the icon must be at least 1 pixel big".to_string()));
+        }
+        if let Some(value) = self.progress.value.filter(|value| *value > MAX_PROGRESS) {
+            return Err(("progress.value", format!("{}% is above the maximum of {}%", value, MAX_PROGRESS)));
+        }
+        if self.progress.length == 0 {
+            return Err(("progress.length", "the bar must be at least 1 pixel long".to_string()));
+        }
+        if self.progress.height == 0 {
+            return Err(("progress.height", "the bar must be at least 1 pixel thick".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn init_toml_config(config_name: Option<String>) -> Result<Config, ConfigError> {
+
+    // A specified config must exist. Otherwise the default one is used if the user has created it,
+    // a specified config is applied over it only if it says so with `extends = "default"`
+    let config_path = match config_name {
+        Some(conf_name) => Some(find_config_file(&conf_name).ok_or_else(|| ConfigError::NotFound {
+            name: conf_name.clone(),
+            searched: config_dirs(),
+        })?),
+        None => find_named_config(DEFAULT_CONFIG_NAME),
+    };
+
+    let mut layers: Vec<ConfigLayer> = Vec::new();
+    if let Some(path) = config_path {
+        load_config_layers(&path, &mut Vec::new(), &mut layers)?;
+    }
+
+    // Merge all the (possibly partial) configs over the built-in one
+    let mut merged: Value = Value::Table(DEFAULT_CONFIG.parse().expect("Invalid DEFAULT_CONFIG"));
+    for layer in layers.iter() {
+        merge_values(&mut merged, layer.value.clone());
+    }
+
+    let invalid_value = |key: &str, message: String| match find_layer(&layers, key) {
+        Some(layer) => layer.invalid_value(key, message),
+        None => ConfigError::InvalidValue { location: None, key: key.to_string(), message },
+    };
+
+    let mut warn_unknown_key = |key: serde_ignored::Path| {
+        let key = key.to_string();
+        match find_layer(&layers, &key).and_then(|layer| layer.locate(&key)) {
+            Some(location) => eprintln!("Warning: {}: unknown key `{}`, ignored", location, key),
+            None => eprintln!("Warning: unknown key `{}`, ignored", key),
+        }
+    };
+
+    let mut config: Config = serde_path_to_error::deserialize(serde_ignored::Deserializer::new(merged, &mut warn_unknown_key))
+        .map_err(|err| invalid_value(&err.path().to_string(), err.inner().message().to_string()))?;
+
+    // The margins of the old configs still apply to the sides they used to
+    let warn_deprecated = |key: &str, replacement: &str| match find_layer(&layers, key).and_then(|layer| layer.locate(key)) {
+        Some(location) => eprintln!("Warning: {}: `{}` is deprecated, use {} instead", location, key, replacement),
+        None => eprintln!("Warning: `{}` is deprecated, use {} instead", key, replacement),
+    };
+    if let Some(percentage) = config.margins.vertical_percentage {
+        warn_deprecated("margins.vertical_percentage", "`margins.top` and `margins.bottom`");
+        config.margins.top = Length::Percent(percentage);
+        config.margins.bottom = Length::Percent(percentage);
+    }
+    if let Some(percentage) = config.margins.horizontal_percentage {
+        warn_deprecated("margins.horizontal_percentage", "`margins.left` and `margins.right`");
+        config.margins.left = Length::Percent(percentage);
+        config.margins.right = Length::Percent(percentage);
+    }
+
+    config.validate().map_err(|(key, message)| invalid_value(key, message))?;
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[derive(Deserialize)]
+    struct Alignments {
+        text: TextAlignment,
+        vertical: VerticalAlignment,
+    }
+
+    fn alignments(source: &str) -> Result<Alignments, toml::de::Error> {
+        toml::from_str(source)
+    }
+
+    #[test]
+    fn alignments_are_case_insensitive_in_the_config() {
+        let parsed = alignments("text = 'LEFT'\nvertical = 'bottom'").unwrap();
+        assert!(matches!(parsed.text, TextAlignment::Left));
+        assert_eq!(parsed.vertical, VerticalAlignment::Bottom);
+
+        // The spellings of the old configs
+        let parsed = alignments("text = 'Right'\nvertical = 'Top'").unwrap();
+        assert!(matches!(parsed.text, TextAlignment::Right));
+        assert_eq!(parsed.vertical, VerticalAlignment::Top);
+    }
+
+    #[test]
+    fn default_config_extends_the_next_one_with_its_name() {
+
+        let root = env::temp_dir().join(format!("gwstuff-extends-{}", std::process::id()));
+        let (user, system) = (root.join("user"), root.join("system"));
+        fs::create_dir_all(user.join("gwstuff")).unwrap();
+        fs::create_dir_all(system.join("gwstuff")).unwrap();
+        fs::write(user.join("gwstuff/default.toml"), "extends = 'default'\n[font]\nsize = 20").unwrap();
+        fs::write(system.join("gwstuff/default.toml"), "[font]\nintra_line = 3.0").unwrap();
+
+        env::set_var("XDG_CONFIG_HOME", &user);
+        env::set_var("XDG_CONFIG_DIRS", &system);
+        let config = init_toml_config(None);
+        fs::remove_dir_all(&root).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.font.size, 20.0);
+        assert_eq!(config.font.intra_line, 3.0);
+    }
+
+    #[test]
+    fn unknown_alignment_is_an_error() {
+        let err = alignments("text = 'middle'\nvertical = 'center'").err().unwrap();
+        assert!(err.message().contains("expected one of: center, left, right"));
+    }
 }
@@ -0,0 +1,451 @@
+use std::ops::Range;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::background::{Background, BackgroundImage};
+use crate::canvas::{Canvas, Paint};
+use crate::composite::{self, Argb};
+use crate::font::{FontError, Fonts};
+use crate::glyph;
+use crate::icon;
+use crate::image::{Image, ImageError};
+use crate::markup::StyledText;
+use crate::parser::{self, expand_home, Config, IconPosition, Length, ProgressPosition, ProgressProps, ProgressShape, VerticalAlignment, WindowProps};
+use crate::text::{self, Line, LineGlyph};
+
+/// Fonts and images used to draw the box, loaded once for every output
+pub struct Resources {
+    pub fonts: Fonts,
+    pub background_image: Option<Image>,
+    /// Already scaled to the icon size
+    pub icon: Option<Image>,
+}
+
+#[derive(Debug, Error)]
+pub enum ResourceError {
+    #[error(transparent)]
+    Font(#[from] FontError),
+
+    #[error(transparent)]
+    Image(#[from] ImageError),
+
+    #[error("icon '{name}' not found in {}", match theme {
+        Some(theme) => format!("the icon theme '{}' or in hicolor", theme),
+        None => "hicolor, the icon theme can be set with icon.theme".to_string(),
+    })]
+    IconNotFound { name: String, theme: Option<String> },
+}
+
+impl Resources {
+    /// Load what the config refers to, failing if any of it is missing
+    pub fn load(config: &Config) -> Result<Self, ResourceError> {
+
+        let fonts = Fonts::new(&config.font)?;
+        let background_image = match &config.window.background_image {
+            Some(path) => Some(Image::load(&expand_home(path))?),
+            None => None,
+        };
+        let icon = match &config.icon.path {
+            Some(path) => Some(Image::load_fit(&icon_path(path, config)?, config.icon.size)?),
+            None => None,
+        };
+
+        Ok(Resources { fonts, background_image, icon })
+    }
+}
+
+/// The file of the icon `path`: a path (or a file:// URI), an existing file or a name
+/// with an image extension is taken as it is, any other name is looked up in the icon theme
+fn icon_path(path: &str, config: &Config) -> Result<PathBuf, ResourceError> {
+
+    let path = path.strip_prefix("file://").unwrap_or(path);
+    let file = expand_home(path);
+    let extension = file.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+    let image_extension = matches!(extension.as_deref(), Some("png" | "jpg" | "jpeg" | "svg" | "svgz"));
+    if path.contains('/') || image_extension || file.is_file() {
+        return Ok(file);
+    }
+
+    let theme = config.icon.theme.clone().or_else(icon::gtk_icon_theme);
+    icon::find_icon(path, theme.as_deref(), config.icon.size)
+        .ok_or_else(|| ResourceError::IconNotFound { name: path.to_string(), theme })
+}
+
+/// Minimum and maximum size of the box along one axis, the percentages are relative to `output_size`.
+/// The box is never bigger than the output minus the `shadow` around it along the axis
+fn size_bounds(fixed: Option<Length>, min: Option<Length>, max: Option<Length>, output_size: u32, shadow: u32) -> (u32, u32) {
+
+    let room = output_size.saturating_sub(shadow);
+    let to_px = |length: Length| (length.to_px(output_size).max(0) as u32).min(room);
+
+    match fixed {
+        Some(size) => (to_px(size), to_px(size)),
+        None => {
+            let min = min.map_or(0, to_px);
+            (min, max.map_or(room, to_px).max(min))
+        }
+    }
+}
+
+/// Calc the dimensions of the surface and render the box with its shadow,
+/// `output_size` is the logical size of the output the box is shown on
+pub fn get_dimensions_and_canvas(config: &Config, resources: &Resources, text: &[StyledText], output_size: (u32, u32)) -> ((u32, u32), Vec<Argb>) {
+
+    let window = &config.window;
+
+    // The surface holds the shadow too, it must fit in the output with it
+    let (shadow_top, shadow_right, shadow_bottom, shadow_left) = shadow_extents(window);
+    let width_bounds = size_bounds(window.width, window.min_width, window.max_width, output_size.0, shadow_left + shadow_right);
+    let height_bounds = size_bounds(window.height, window.min_height, window.max_height, output_size.1, shadow_top + shadow_bottom);
+
+    let inset = window.content_inset();
+    let max_content = (
+        width_bounds.1.saturating_sub(2 * inset.0),
+        height_bounds.1.saturating_sub(2 * inset.1),
+    );
+
+    // The text and the progress bar take what's left by the icon, the text what's left by the bar
+    let icon = resources.icon.as_ref().map(|icon| (icon.width, icon.height));
+    let spacing = config.icon.spacing;
+    let max_block = match (icon, config.icon.position) {
+        (None, _) => max_content,
+        (Some((icon_w, _)), IconPosition::Left | IconPosition::Right) => (max_content.0.saturating_sub(icon_w + spacing), max_content.1),
+        (Some((_, icon_h)), IconPosition::Top) => (max_content.0, max_content.1.saturating_sub(icon_h + spacing)),
+    };
+    let progress = &config.progress;
+    let (bar_w, bar_h) = progress.size();
+    let max_text = match (progress.value, progress.position) {
+        (None, _) => max_block,
+        (Some(_), ProgressPosition::Bottom) => (max_block.0, max_block.1.saturating_sub(bar_h + progress.spacing)),
+        (Some(_), ProgressPosition::Right) => (max_block.0.saturating_sub(bar_w + progress.spacing), max_block.1),
+    };
+
+    let lines = text::layout_text(config, &resources.fonts, text, max_text);
+
+    let text_size = (lines.iter().map(|line| line.width).max().unwrap_or(0), text::text_height(&lines, config.font.intra_line));
+    let block = text_block_size(progress, text_size, !lines.is_empty());
+    let content = match (icon, config.icon.position) {
+        (None, _) => block,
+        (Some((icon_w, icon_h)), IconPosition::Left | IconPosition::Right) => (icon_w + spacing + block.0, icon_h.max(block.1)),
+        (Some((icon_w, icon_h)), IconPosition::Top) => (icon_w.max(block.0), icon_h + spacing + block.1),
+    };
+    let win_w = (content.0 + 2 * inset.0).clamp(width_bounds.0, width_bounds.1);
+    let win_h = (content.1 + 2 * inset.1).clamp(height_bounds.0, height_bounds.1);
+
+    let canvas = add_shadow(window, get_canvas(config, resources, &lines, (win_w, win_h)));
+    ((canvas.width, canvas.height), canvas.pixels)
+}
+
+/// A rectangle in the box
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Rect {
+    fn x_range(&self) -> Range<i32> {
+        self.x..(self.x + self.width)
+    }
+
+    fn y_range(&self) -> Range<i32> {
+        self.y..(self.y + self.height)
+    }
+}
+
+/// Offset of something `size` long aligned in `space`
+fn align_offset(alignment: VerticalAlignment, space: i32, size: i32) -> i32 {
+    match alignment {
+        VerticalAlignment::Top => 0,
+        VerticalAlignment::Center => (space - size) / 2,
+        VerticalAlignment::Bottom => space - size,
+    }
+}
+
+/// Size of the text together with the progress bar, if there is one
+fn text_block_size(progress: &ProgressProps, text_size: (u32, u32), has_text: bool) -> (u32, u32) {
+
+    if progress.value.is_none() {
+        return text_size;
+    }
+    let spacing = if has_text { progress.spacing } else { 0 };
+    let (length, height) = progress.size();
+
+    match progress.position {
+        ProgressPosition::Bottom => (text_size.0.max(length), text_size.1 + spacing + height),
+        ProgressPosition::Right => (text_size.0 + spacing + length, text_size.1.max(height)),
+    }
+}
+
+/// Where the content goes in the `content` area of the box: the rectangle of the icon, and the area
+/// of the text and the progress bar (where they are aligned and clipped) with the top of the two
+fn layout_content(config: &Config, icon: Option<(u32, u32)>, text_height: i32, content: Rect) -> (Option<Rect>, (Rect, i32)) {
+
+    let spacing = config.icon.spacing as i32;
+    let vertical_alignment = config.window.vertical_alignment;
+
+    let Some((icon_w, icon_h)) = icon.map(|(w, h)| (w as i32, h as i32)) else {
+        let text_y = content.y + align_offset(vertical_alignment, content.height, text_height);
+        return (None, (content, text_y));
+    };
+
+    match config.icon.position {
+        IconPosition::Left | IconPosition::Right => {
+            // The icon and the text are aligned to each other, then together in the box
+            let block_h = icon_h.max(text_height);
+            let block_y = content.y + align_offset(vertical_alignment, content.height, block_h);
+            let (icon_x, text_x) = match config.icon.position {
+                IconPosition::Left => (content.x, content.x + icon_w + spacing),
+                _ => (content.x + content.width - icon_w, content.x),
+            };
+            let icon_rect = Rect {
+                x: icon_x,
+                y: block_y + align_offset(config.icon.vertical_alignment, block_h, icon_h),
+                width: icon_w,
+                height: icon_h,
+            };
+            let text_column = Rect { x: text_x, y: content.y, width: content.width - icon_w - spacing, height: content.height };
+            (Some(icon_rect), (text_column, block_y + align_offset(config.icon.vertical_alignment, block_h, text_height)))
+        }
+        IconPosition::Top => {
+            // The icon follows the alignment of the lines below it
+            let block_y = content.y + align_offset(vertical_alignment, content.height, icon_h + spacing + text_height);
+            let icon_x = match config.font.text_alignment {
+                parser::TextAlignment::Left => content.x,
+                parser::TextAlignment::Center => content.x + (content.width - icon_w) / 2,
+                parser::TextAlignment::Right => content.x + content.width - icon_w,
+            };
+            let icon_rect = Rect { x: icon_x, y: block_y, width: icon_w, height: icon_h };
+            let text_top = block_y + icon_h + spacing;
+            let text_area = Rect { x: content.x, y: text_top, width: content.width, height: content.y + content.height - text_top };
+            (Some(icon_rect), (text_area, text_top))
+        }
+    }
+}
+
+/// Split the area of the text and the progress bar, with `block_y` the top of the two:
+/// the rectangle of the bar, and the area of the text with the top of the first line
+fn layout_progress(progress: &ProgressProps, area: Rect, block_y: i32, text_height: i32, has_text: bool) -> (Option<Rect>, (Rect, i32)) {
+
+    if progress.value.is_none() {
+        return (None, (area, block_y));
+    }
+    let spacing = if has_text { progress.spacing as i32 } else { 0 };
+    let (length, height) = progress.size();
+    let (length, height) = (length as i32, height as i32);
+
+    match progress.position {
+        ProgressPosition::Bottom => {
+            // Below the text the bar is as long as the area, a ring is centered in it
+            let bar = match progress.shape {
+                ProgressShape::Bar => Rect { x: area.x, y: block_y + text_height + spacing, width: area.width, height },
+                ProgressShape::Ring => Rect { x: area.x + (area.width - length) / 2, y: block_y + text_height + spacing, width: length, height },
+            };
+            (Some(bar), (area, block_y))
+        }
+        ProgressPosition::Right => {
+            // The bar and the text are centered to each other
+            let block_h = text_height.max(height);
+            let bar = Rect { x: area.x + area.width - length, y: block_y + (block_h - height) / 2, width: length, height };
+            let text_area = Rect { width: area.width - length - spacing, ..area };
+            (Some(bar), (text_area, block_y + (block_h - text_height) / 2))
+        }
+    }
+}
+
+/// Space taken by the shadow around the box: top, right, bottom, left
+pub fn shadow_extents(window: &WindowProps) -> (u32, u32, u32, u32) {
+
+    if window.shadow_color.a == 0 {
+        return (0, 0, 0, 0);
+    }
+    let blur = window.shadow_blur_radius as i32;
+    let (x, y) = window.shadow_offset;
+    let side = |offset: i32| (blur + offset).max(0) as u32;
+
+    (side(-y), side(x), side(y), side(-x))
+}
+
+/// Place the box `canvas` over its shadow, in a canvas grown by the `shadow_extents`.
+/// The shadow is not drawn below the box, not to show through a translucent background
+fn add_shadow(window: &WindowProps, canvas: Canvas) -> Canvas {
+
+    let (top, right, bottom, left) = shadow_extents(window);
+    if (top, right, bottom, left) == (0, 0, 0, 0) {
+        return canvas;
+    }
+
+    let dimensions = (canvas.width, canvas.height);
+    let size = (dimensions.0 + left + right, dimensions.1 + top + bottom);
+    let shadow = composite::premultiply(window.shadow_color);
+    let radius = corner_radius(window, dimensions);
+    let sigma = window.shadow_blur_radius as f32 / 2.0;
+
+    let box_rect = (left as f32, top as f32, (left + dimensions.0) as f32, (top + dimensions.1) as f32);
+    let (offset_x, offset_y) = (window.shadow_offset.0 as f32, window.shadow_offset.1 as f32);
+    let shadow_rect = (box_rect.0 + offset_x, box_rect.1 + offset_y, box_rect.2 + offset_x, box_rect.3 + offset_y);
+
+    let mut surface = Canvas::new(size.0, size.1);
+    surface.rounded_rect_with(shadow_rect, radius, sigma, |pixel, _, coverage| *pixel = composite::scale(shadow, coverage));
+    surface.rounded_rect_with(box_rect, radius, 0.0, |pixel, _, coverage| *pixel = composite::scale(*pixel, 1.0 - coverage));
+    surface.draw_pixels((left as i32, top as i32), canvas.width, &canvas.pixels);
+
+    surface
+}
+
+fn get_canvas(config: &Config, resources: &Resources, lines: &[Line], dimensions: (u32, u32)) -> Canvas {
+
+    let mut canvas = set_backgorund(config, resources.background_image.as_ref(), dimensions);
+
+    // The text is drawn only inside the border and the paddings, what overflows is clipped
+    let (h_padding, v_padding) = config.window.content_inset();
+    let content = Rect {
+        x: h_padding as i32,
+        y: v_padding as i32,
+        width: dimensions.0 as i32 - 2 * h_padding as i32,
+        height: dimensions.1 as i32 - 2 * v_padding as i32,
+    };
+
+    // The contrast raises the partial coverage of the edges, leaving the inside of the strokes as it is
+    let coverage_exponent = 1.0 / (1.0 + config.font.contrast);
+
+    let text_h = text::text_height(lines, config.font.intra_line) as i32;
+    let block_h = text_block_size(&config.progress, (0, text_h as u32), !lines.is_empty()).1 as i32;
+    let icon = resources.icon.as_ref();
+    let (icon_rect, (block_area, block_y)) = layout_content(config, icon.map(|icon| (icon.width, icon.height)), block_h, content);
+    let (bar_rect, (text_area, text_y)) = layout_progress(&config.progress, block_area, block_y, text_h, !lines.is_empty());
+
+    canvas.set_clip(content.x_range(), content.y_range());
+    if let (Some(icon), Some(rect)) = (icon, icon_rect) {
+        canvas.draw_image(icon, (rect.x, rect.y));
+    }
+    if let Some(rect) = bar_rect {
+        draw_progress(&mut canvas, &config.progress, rect);
+    }
+
+    // The text is drawn only inside its area, what overflows is clipped
+    let clip_x = text_area.x_range().start.max(content.x)..text_area.x_range().end.min(content.x + content.width);
+    let clip_y = text_area.y_range().start.max(content.y)..text_area.y_range().end.min(content.y + content.height);
+    canvas.set_clip(clip_x, clip_y);
+    let mut init_y = text_y as f32;
+
+    for line in lines.iter() {
+
+        // In a right to left paragraph the alignment is relative to the start and the end of the line
+        let init_x: i32 = match (config.font.text_alignment, line.rtl) {
+            (parser::TextAlignment::Left, false) | (parser::TextAlignment::Right, true) => text_area.x,
+            (parser::TextAlignment::Right, false) | (parser::TextAlignment::Left, true) => text_area.x + text_area.width - line.width as i32,
+            (parser::TextAlignment::Center, _) => text_area.x + (text_area.width - line.width as i32) / 2,
+        };
+        let line_y = (init_y + line.ascent).round() as i32;
+
+        for LineGlyph { font, glyph: g, color } in line.glyphs.iter() {
+
+            // Emoji are drawn in their own colors
+            if let Some(color_glyph) = glyph::color_glyph(font, g, *color) {
+                canvas.draw_pixels((init_x + color_glyph.x, line_y + color_glyph.y), color_glyph.width, &color_glyph.pixels);
+                continue;
+            }
+
+            if let Some(bb) = g.pixel_bounding_box() {
+                g.draw(|x, y, v| {
+
+                    // v should be in the range 0.0 to 1.0
+                    let x = init_x + x as i32 + bb.min.x;
+                    let y = line_y + y as i32 + bb.min.y;
+
+                    let coverage = v.powf(coverage_exponent);
+                    if config.font.linear_blending {
+                        canvas.blend_linear(x, y, *color, coverage);
+                    } else {
+                        canvas.blend(x, y, *color, coverage);
+                    }
+                })
+            }
+        }
+        for underline in line.underlines.iter() {
+            let (left, right) = ((init_x as f32 + underline.x.start).round(), (init_x as f32 + underline.x.end).round());
+            let top = (line_y as f32 + underline.y).round();
+            let bottom = top + underline.thickness.round().max(1.0);
+            canvas.line((left, (top + bottom) / 2.0), (right, (top + bottom) / 2.0), bottom - top, underline.color);
+        }
+
+        init_y += line.height() + config.font.intra_line;
+    }
+
+    canvas.reset_clip();
+    canvas
+}
+
+/// Draw the progress bar in `rect`: the track, the part filled up to the value and, above 100%,
+/// the overflowing part again from the start of the bar
+fn draw_progress(canvas: &mut Canvas, progress: &ProgressProps, rect: Rect) {
+
+    let value = progress.value.unwrap_or(0) as f32 / 100.0;
+    if progress.shape == ProgressShape::Ring {
+        return draw_progress_ring(canvas, progress, rect, value);
+    }
+    let radius = progress.corner_radius as f32;
+    let (left, top, right, bottom) = (rect.x as f32, rect.y as f32, (rect.x + rect.width) as f32, (rect.y + rect.height) as f32);
+    let bar = |fraction: f32| (left, top, left + (right - left) * fraction.min(1.0), bottom);
+
+    canvas.rounded_rect(bar(1.0), radius, Paint::Fill, progress.track_color);
+    canvas.rounded_rect(bar(value), radius, Paint::Fill, progress.fill_color);
+    if let Some(overflow_color) = progress.overflow_color.filter(|_| value > 1.0) {
+        canvas.rounded_rect(bar(value - 1.0), radius, Paint::Fill, overflow_color);
+    }
+}
+
+/// Draw the progress as a ring in the square `rect`, filled clockwise from the top
+fn draw_progress_ring(canvas: &mut Canvas, progress: &ProgressProps, rect: Rect, value: f32) {
+
+    let thickness = (progress.height as f32).min(rect.width as f32 / 2.0);
+    let center = (rect.x as f32 + rect.width as f32 / 2.0, rect.y as f32 + rect.height as f32 / 2.0);
+    let radius = (rect.width as f32 - thickness) / 2.0;
+    let ring = Paint::Stroke(thickness);
+    let sweep = |fraction: f32| (0.0, 360.0 * fraction.min(1.0));
+
+    canvas.circle(center, radius, ring, progress.track_color);
+    canvas.arc(center, radius, sweep(value), ring, progress.fill_color);
+    if let Some(overflow_color) = progress.overflow_color.filter(|_| value > 1.0) {
+        canvas.arc(center, radius, sweep(value - 1.0), ring, overflow_color);
+    }
+}
+
+/// Fill the canvas with the box: the background and its image inside the border,
+/// the border and nothing outside of the rounded corners
+fn set_backgorund (config: &Config, image: Option<&Image>, dimensions: (u32, u32)) -> Canvas {
+
+    let window = &config.window;
+    let background = Background::new(window, dimensions);
+    let image = image.map(|image| BackgroundImage::new(window, image, dimensions));
+
+    let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+    let radius = corner_radius(window, dimensions);
+    let border_width = window.border_width as f32;
+    let inner = (border_width, border_width, width - border_width, height - border_width);
+
+    let mut canvas = Canvas::new(dimensions.0, dimensions.1);
+    if border_width > 0.0 {
+        canvas.rounded_rect((0.0, 0.0, width, height), radius, Paint::Fill, window.border_color);
+    }
+    // The background replaces the border instead of being drawn over it,
+    // so that no seam shows through where the two antialiased edges meet
+    canvas.rounded_rect_with(inner, (radius - border_width).max(0.0), 0.0, |pixel, (x, y), coverage| {
+        let mut inside = background.pixel(x as u32, y as u32);
+        if let Some(image) = &image {
+            inside = composite::over(inside, image.pixel(x as u32, y as u32));
+        }
+        *pixel = composite::add(composite::scale(inside, coverage), composite::scale(*pixel, 1.0 - coverage));
+    });
+
+    canvas
+}
+
+/// Radius of the corners of the box, no more than half of its sides
+fn corner_radius(window: &WindowProps, dimensions: (u32, u32)) -> f32 {
+    (window.corner_radius as f32).min(dimensions.0 as f32 / 2.0).min(dimensions.1 as f32 / 2.0)
+}
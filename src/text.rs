@@ -0,0 +1,441 @@
+use std::ops::Range;
+use std::rc::Rc;
+
+use rusttype::{point, GlyphId, PositionedGlyph};
+use rustybuzz::{Direction, UnicodeBuffer};
+use unicode_bidi::{BidiInfo, Level};
+use unicode_script::{Script, UnicodeScript};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::color::Color;
+use crate::font::{font_scale, FontChain, FontFace, FontVariant, Fonts};
+use crate::markup::{self, Style, StyledText};
+use crate::parser::{Config, Overflow};
+
+/// Character appended to the lines cut by the ellipsis overflow
+const ELLIPSIS: char = '…';
+
+/// Smallest font size (in points) the shrink overflow can reach
+const MIN_FONT_SIZE: f32 = 4.0;
+
+/// A glyph with the font it comes from
+pub struct LineGlyph {
+    pub font: Rc<FontFace>,
+    pub glyph: PositionedGlyph<'static>,
+    pub color: Color,
+}
+
+/// A line under a run of glyphs, `y` is the top of the line from the baseline
+pub struct Underline {
+    pub x: Range<f32>,
+    pub y: f32,
+    pub thickness: f32,
+    pub color: Color,
+}
+
+/// Glyphs of a line in visual order, positioned on a baseline at y = 0
+pub struct Line {
+    pub glyphs: Vec<LineGlyph>,
+    pub underlines: Vec<Underline>,
+    pub width: u32,
+    /// Highest ascent and lowest descent (positive) among the fonts used in the line
+    pub ascent: f32,
+    pub descent: f32,
+    /// The paragraph the line comes from is right to left
+    pub rtl: bool,
+    /// Byte offset in the text of the character each glyph comes from, with the advance of the glyph
+    advances: Vec<(usize, f32)>,
+}
+
+/// A paragraph is right to left if its first strong character is
+fn is_rtl(text: &str) -> bool {
+    unicode_bidi::get_base_direction(text) == unicode_bidi::Direction::Rtl
+}
+
+/// Split `text` in runs of the same script, the characters shared by
+/// several scripts (spaces, punctuation, combining marks) join the run they are in
+fn script_runs(text: &str) -> Vec<(Range<usize>, Script)> {
+
+    let mut runs: Vec<(Range<usize>, Script)> = Vec::new();
+
+    for (index, c) in text.char_indices() {
+        let script = c.script();
+        let end = index + c.len_utf8();
+
+        match runs.last_mut() {
+            Some((range, run_script)) if script == *run_script || script == Script::Common || script == Script::Inherited => {
+                range.end = end;
+            }
+            // A run of only common characters takes the script of the ones that follow
+            Some((range, run_script)) if *run_script == Script::Common => {
+                range.end = end;
+                *run_script = script;
+            }
+            _ => runs.push((index..end, script)),
+        }
+    }
+
+    runs
+}
+
+/// Split the run `text` in runs rendered with the same font of `fonts`,
+/// each grapheme cluster is rendered with a single font
+fn font_runs(fonts: &FontChain, text: &str) -> Vec<(Range<usize>, usize)> {
+
+    let mut runs: Vec<(Range<usize>, usize)> = Vec::new();
+
+    for (index, grapheme) in text.grapheme_indices(true) {
+        // Spaces and punctuation stay in the font of the run they are in
+        let shared = grapheme.chars().all(|c| matches!(c.script(), Script::Common | Script::Inherited) && !c.is_alphanumeric());
+        let current = runs.last().map(|(_, font)| *font).filter(|_| shared);
+        let font = fonts.font_for(grapheme, current);
+        let end = index + grapheme.len();
+
+        match runs.last_mut() {
+            Some((range, run_font)) if *run_font == font => range.end = end,
+            _ => runs.push((index..end, font)),
+        }
+    }
+
+    runs
+}
+
+/// Shape a run of a single script and direction, the glyphs are returned in visual order
+fn shape_run(face: &rustybuzz::Face, text: &str, script: Script, rtl: bool) -> rustybuzz::GlyphBuffer {
+
+    let mut buffer = UnicodeBuffer::new();
+    buffer.push_str(text);
+    buffer.set_direction(if rtl { Direction::RightToLeft } else { Direction::LeftToRight });
+
+    let tag = rustybuzz::ttf_parser::Tag::from_bytes_lossy(script.short_name().as_bytes());
+    if let Some(script) = rustybuzz::Script::from_iso15924_tag(tag) {
+        buffer.set_script(script);
+    }
+
+    rustybuzz::shape(face, &[], buffer)
+}
+
+/// Part of a line shaped with a single font, style, script and direction
+struct ShapingRun {
+    range: Range<usize>,
+    script: Script,
+    style: Style,
+    fonts: Rc<FontChain>,
+    font: usize,
+}
+
+fn variant(style: &Style) -> FontVariant {
+    FontVariant { bold: style.bold, italic: style.italic }
+}
+
+/// Shape a line of text, reordering its runs with the Unicode bidi algorithm.
+/// `rtl` is the direction of the paragraph the line belongs to, the font
+/// sizes of the styles are multiplied by `size_factor`
+pub fn layout_line(fonts: &Fonts, text: &StyledText, rtl: bool, size_factor: f32) -> Line {
+
+    let mut glyphs: Vec<LineGlyph> = Vec::new();
+    let mut underlines: Vec<Underline> = Vec::new();
+    let mut advances: Vec<(usize, f32)> = Vec::new();
+    let mut x: f32 = 0.0;
+
+    // An empty line is as high as the font it would be written with
+    let style = text.style_at(0);
+    let empty_metrics = fonts.chain(variant(&style)).primary().v_metrics(font_scale(style.size * size_factor));
+    let mut ascent = empty_metrics.ascent;
+    let mut descent = -empty_metrics.descent;
+
+    let bidi_info = BidiInfo::new(&text.text, Some(if rtl { Level::rtl() } else { Level::ltr() }));
+
+    for paragraph in bidi_info.paragraphs.iter() {
+        let (levels, runs) = bidi_info.visual_runs(paragraph, paragraph.range.clone());
+
+        for run in runs {
+            let run_rtl = levels[run.start].is_rtl();
+
+            // The script, style and font runs are in logical order, that is reversed in a right to left run
+            let mut shaping_runs: Vec<ShapingRun> = Vec::new();
+            for (range, script) in script_runs(&text.text[run.clone()]) {
+                let range = (run.start + range.start)..(run.start + range.end);
+
+                for (range, style) in text.spans_in(range) {
+                    let chain = fonts.chain(variant(&style));
+                    for (font_range, font) in font_runs(&chain, &text.text[range.clone()]) {
+                        let font_range = (range.start + font_range.start)..(range.start + font_range.end);
+                        shaping_runs.push(ShapingRun { range: font_range, script, style, fonts: chain.clone(), font });
+                    }
+                }
+            }
+            if run_rtl {
+                shaping_runs.reverse();
+            }
+
+            for ShapingRun { range, script, style, fonts: chain, font } in shaping_runs {
+                let font = chain.face(font);
+                let scale = font_scale(style.size * size_factor);
+                let px_per_unit = font.px_per_unit(scale);
+
+                let metrics = font.v_metrics(scale);
+                ascent = ascent.max(metrics.ascent);
+                descent = descent.max(-metrics.descent);
+
+                let shaped = shape_run(font.shaping_face(), &text.text[range.clone()], script, run_rtl);
+                let run_x = x;
+
+                for (info, position) in shaped.glyph_infos().iter().zip(shaped.glyph_positions()) {
+                    let glyph = font.font
+                        .glyph(GlyphId(info.glyph_id as u16))
+                        .scaled(scale)
+                        .positioned(point(
+                            x + position.x_offset as f32 * px_per_unit,
+                            -position.y_offset as f32 * px_per_unit,
+                        ));
+                    glyphs.push(LineGlyph { font: font.clone(), glyph, color: style.color });
+                    let advance = position.x_advance as f32 * px_per_unit;
+                    advances.push((range.start + info.cluster as usize, advance));
+                    x += advance;
+                }
+
+                if style.underline {
+                    let (y, thickness) = font.underline(scale);
+                    descent = descent.max(y + thickness);
+                    underlines.push(Underline { x: run_x..x, y, thickness, color: style.color });
+                }
+            }
+        }
+    }
+
+    Line { glyphs, underlines, width: x.ceil() as u32, ascent, descent, rtl, advances }
+}
+
+impl Line {
+    pub fn height(&self) -> f32 {
+        self.ascent + self.descent
+    }
+}
+
+pub fn text_height(lines: &[Line], intra_line: f32) -> u32 {
+    let lines_height: f32 = lines.iter().map(Line::height).sum();
+    (lines_height + lines.len().saturating_sub(1) as f32 * intra_line).ceil() as u32
+}
+
+/// Width of the parts of a shaped line, from the advances of the glyphs of their characters
+struct Advances {
+    /// Width of the glyphs of the characters before each byte of the text
+    before: Vec<f32>,
+}
+
+impl Advances {
+    fn new(line: &Line, text: &str) -> Self {
+
+        let mut before = vec![0.0; text.len() + 1];
+        for &(cluster, advance) in line.advances.iter() {
+            before[cluster + 1] += advance;
+        }
+        for index in 1..before.len() {
+            before[index] += before[index - 1];
+        }
+
+        Advances { before }
+    }
+
+    /// Width of the glyphs of the characters in `range`. It's the one of the range shaped on its own
+    /// except for the kerning and the ligatures across its ends
+    fn width(&self, range: Range<usize>) -> f32 {
+        self.before[range.end] - self.before[range.start]
+    }
+}
+
+/// `range` of `text` without the trailing whitespaces
+fn trim_end(text: &str, range: Range<usize>) -> Range<usize> {
+    range.start..range.start + text[range].trim_end().len()
+}
+
+/// Position of the first character of `text` from `index` that is not a whitespace
+fn skip_whitespaces(text: &str, index: usize) -> usize {
+    text.len() - text[index..].trim_start().len()
+}
+
+/// Split `text` in lines not wider than `max_width` at the line break opportunities of
+/// the Unicode line breaking algorithm, a word wider than `max_width` is broken between its
+/// characters. The text is shaped once to place the breaks, then each line is shaped on its own
+fn wrap_text(fonts: &Fonts, size_factor: f32, text: &StyledText, rtl: bool, max_width: u32) -> Vec<Line> {
+
+    let whole = layout_line(fonts, text, rtl, size_factor);
+    if whole.width <= max_width {
+        return vec![whole];
+    }
+    let advances = Advances::new(&whole, &text.text);
+
+    // The line from `start` to the furthest of `ends` (ascending) that fits, checked by shaping it
+    let longest_line = |start: usize, ends: &[usize]| -> Option<(Range<usize>, Line)> {
+        let fitting = ends.partition_point(|&end| advances.width(trim_end(&text.text, start..end)) <= max_width as f32);
+        ends[..fitting]
+            .iter()
+            .rev()
+            .map(|&end| trim_end(&text.text, start..end))
+            .filter(|range| !range.is_empty())
+            .find_map(|range| {
+                let line = layout_line(fonts, &text.slice(range.clone()), rtl, size_factor);
+                (line.width <= max_width).then_some((range, line))
+            })
+    };
+
+    // A line ends before the words that follow the break, after the whitespaces
+    let breaks: Vec<usize> = unicode_linebreak::linebreaks(&text.text).map(|(index, _)| index).collect();
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut start = skip_whitespaces(&text.text, 0);
+
+    while start < text.text.len() {
+        let ends = &breaks[breaks.partition_point(|&end| end <= start)..];
+
+        let (range, line) = longest_line(start, ends).unwrap_or_else(|| {
+            // The word doesn't fit, its first characters (at least one) go on their own line
+            // and the others can be followed by the next words
+            let word_end = ends.first().copied().unwrap_or(text.text.len());
+            let graphemes: Vec<usize> = text.text[start..word_end]
+                .grapheme_indices(true)
+                .map(|(index, grapheme)| start + index + grapheme.len())
+                .collect();
+            longest_line(start, &graphemes).unwrap_or_else(|| {
+                let range = start..graphemes[0];
+                let line = layout_line(fonts, &text.slice(range.clone()), rtl, size_factor);
+                (range, line)
+            })
+        });
+
+        lines.push(line);
+        start = skip_whitespaces(&text.text, range.end);
+    }
+    if lines.is_empty() {
+        lines.push(layout_line(fonts, &text.slice(0..0), rtl, size_factor));
+    }
+
+    lines
+}
+
+/// The first `end` bytes of `text` without the trailing whitespaces, followed by an ellipsis
+fn cut_with_ellipsis(text: &StyledText, end: usize) -> StyledText {
+
+    let end = text.text[..end].trim_end().len();
+    let mut cut = text.slice(0..end);
+    cut.push_str(&ELLIPSIS.to_string(), text.style_at(end.saturating_sub(1)));
+    cut
+}
+
+/// Cut `text` to fit in `max_width` together with a trailing ellipsis. The text is shaped
+/// once to find where to cut it, then the cut line is shaped on its own
+fn ellipsize(fonts: &Fonts, size_factor: f32, text: &StyledText, rtl: bool, max_width: u32) -> Line {
+
+    let whole = layout_line(fonts, text, rtl, size_factor);
+    if whole.width <= max_width {
+        return whole;
+    }
+    let advances = Advances::new(&whole, &text.text);
+    let ellipsis = StyledText::plain(&ELLIPSIS.to_string(), text.style_at(text.text.len().saturating_sub(1)));
+    let ellipsis_width = layout_line(fonts, &ellipsis, rtl, size_factor).width as f32;
+
+    text.text
+        .grapheme_indices(true)
+        .map(|(index, _)| index)
+        .rev()
+        .filter(|&end| advances.width(trim_end(&text.text, 0..end)) + ellipsis_width <= max_width as f32)
+        .find_map(|end| {
+            let line = layout_line(fonts, &cut_with_ellipsis(text, end), rtl, size_factor);
+            (line.width <= max_width).then_some(line)
+        })
+        .unwrap_or_else(|| layout_line(fonts, &cut_with_ellipsis(text, 0), rtl, size_factor))
+}
+
+/// Style of the text outside of any markup tag
+pub fn base_style(config: &Config) -> Style {
+    Style {
+        bold: false,
+        italic: false,
+        underline: false,
+        color: config.font.color,
+        size: config.font.size,
+    }
+}
+
+/// Parse the markup of `line` when it's enabled, otherwise the whole line has the base style
+pub fn style_line(config: &Config, line: &str) -> Result<StyledText, String> {
+    if config.font.markup {
+        markup::parse(line, base_style(config))
+    } else {
+        Ok(StyledText::plain(line, base_style(config)))
+    }
+}
+
+/// Lay out the text so that it fits in `max_content` (width, height) following
+/// the overflow policy
+pub fn layout_text(config: &Config, fonts: &Fonts, text: &[StyledText], max_content: (u32, u32)) -> Vec<Line> {
+
+    let intra_line = config.font.intra_line;
+
+    // Every argument is a paragraph, the lines it's split into keep its direction
+    let paragraphs: Vec<(StyledText, bool)> = text
+        .iter()
+        .map(|line| (line.clone(), is_rtl(&line.text)))
+        .collect();
+
+    let layout_lines = |lines: &[(StyledText, bool)], size_factor: f32| -> Vec<Line> {
+        lines.iter().map(|(line, rtl)| layout_line(fonts, line, *rtl, size_factor)).collect()
+    };
+
+    match config.window.overflow {
+        Overflow::Clip => layout_lines(&paragraphs, 1.0),
+
+        Overflow::Wrap => paragraphs
+            .iter()
+            .flat_map(|(line, rtl)| wrap_text(fonts, 1.0, line, *rtl, max_content.0))
+            .collect(),
+
+        Overflow::Ellipsis => {
+            let mut lines = paragraphs;
+
+            // Keep only the lines that fit in height, marking that some are missing.
+            // The height of the lines is estimated with the configured font
+            let metrics = fonts.primary().v_metrics(font_scale(config.font.size));
+            let line_step = metrics.ascent - metrics.descent + intra_line;
+            let max_lines = (((max_content.1 as f32 + intra_line) / line_step).floor() as usize).max(1);
+            if lines.len() > max_lines {
+                lines.truncate(max_lines);
+                if let Some((last, _)) = lines.last_mut() {
+                    *last = cut_with_ellipsis(last, last.text.len());
+                }
+            }
+
+            lines.iter().map(|(line, rtl)| ellipsize(fonts, 1.0, line, *rtl, max_content.0)).collect()
+        }
+
+        Overflow::Shrink => {
+            let fits = |lines: &[Line]| {
+                let width = lines.iter().map(|line| line.width).max().unwrap_or(0);
+                width <= max_content.0 && text_height(lines, intra_line) <= max_content.1
+            };
+
+            let lines = layout_lines(&paragraphs, 1.0);
+            if fits(&lines) {
+                return lines;
+            }
+
+            // The glyphs grow with the font size, so the size that fits is estimated from the
+            // configured one. It's lowered in steps of half a point from there until the lines fit
+            let width = lines.iter().map(|line| line.width).max().unwrap_or(0) as f32;
+            let glyphs_height: f32 = lines.iter().map(Line::height).sum();
+            let spacing = lines.len().saturating_sub(1) as f32 * intra_line;
+            let factor = (max_content.0 as f32 / width).min((max_content.1 as f32 - spacing) / glyphs_height).max(0.0);
+            let steps = ((config.font.size * (1.0 - factor) / 0.5).ceil()).max(1.0);
+            let mut size = (config.font.size - steps * 0.5).max(MIN_FONT_SIZE);
+
+            loop {
+                let lines = layout_lines(&paragraphs, size / config.font.size);
+                if fits(&lines) || size <= MIN_FONT_SIZE {
+                    break lines;
+                }
+                size = (size - 0.5).max(MIN_FONT_SIZE);
+            }
+        }
+    }
+}
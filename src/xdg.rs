@@ -0,0 +1,18 @@
+use std::env;
+use std::path::PathBuf;
+
+/// The directory set in the environment variable `var` (e.g. $XDG_CONFIG_HOME), or `home_fallback`
+/// in the home directory if it's not set. Relative paths are invalid and ignored, as if not set
+pub fn xdg_dir(var: &str, home_fallback: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| dirs::home_dir().map(|home| home.join(home_fallback)))
+}
+
+/// The directories listed in the environment variable `var` (e.g. $XDG_CONFIG_DIRS), or in `default`
+/// if it's not set or empty, separated by ':'. The relative paths are ignored
+pub fn xdg_dirs(var: &str, default: &str) -> Vec<PathBuf> {
+    let dirs = env::var_os(var).filter(|var| !var.is_empty()).unwrap_or_else(|| default.into());
+    env::split_paths(&dirs).filter(|dir| dir.is_absolute()).collect()
+}